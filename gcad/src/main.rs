@@ -1,6 +1,6 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use libgcad::{ScriptEngine, BUILTIN_MATERIALS};
+use libgcad::{ScriptEngine, BUILTIN_MATERIALS, DEFAULT_MAX_CALL_DEPTH};
 use std::{fs::File, io::BufWriter, path::PathBuf};
 
 #[derive(Parser, Debug)]
@@ -14,6 +14,46 @@ struct Args {
 	#[clap(short, long)]
 	verbose: bool,
 
+	/// Set a flag that the script can check with flag('name')
+	#[clap(short, long = "flag", value_name = "NAME")]
+	flags: Vec<String>,
+
+	/// Override a param declared by the script, e.g. --set width=120mm
+	#[clap(long = "set", value_name = "NAME=VALUE")]
+	params: Vec<String>,
+
+	/// Seed for rand(), to reproduce a previous program
+	#[clap(long)]
+	seed: Option<u64>,
+
+	/// Maximum depth of nested user function calls
+	#[clap(long, value_name = "DEPTH", default_value_t = DEFAULT_MAX_CALL_DEPTH)]
+	max_call_depth: usize,
+
+	/// Maximum number of loop iterations and user function calls, to stop runaway scripts
+	#[clap(long, value_name = "STEPS")]
+	max_steps: Option<u64>,
+
+	/// Pause before each operation, for stepping through a new program
+	#[clap(long)]
+	pause_operations: bool,
+
+	/// Multiply every feed rate by this, e.g. 0.5 to prove a new program at half speed
+	#[clap(long, value_name = "SCALE", default_value_t = 1.0)]
+	feed_scale: f64,
+
+	/// Multiply every spindle speed by this
+	#[clap(long, value_name = "SCALE", default_value_t = 1.0)]
+	rpm_scale: f64,
+
+	/// Show progress while generating the program
+	#[clap(long)]
+	progress: bool,
+
+	/// Report the time spent in each phase of generation
+	#[clap(long)]
+	timings: bool,
+
 	/// Input file
 	#[clap(required = true)]
 	input: PathBuf,
@@ -22,14 +62,76 @@ struct Args {
 fn main() -> Result<()> {
 	let args = Args::parse();
 
+	// User functions recurse on the native stack, so give the engine plenty of it for the default --max-call-depth
+	std::thread::Builder::new()
+		.stack_size(256 * 1024 * 1024)
+		.spawn(move || run(args))
+		.context("Failed to start the script engine")?
+		.join()
+		.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+fn run(args: Args) -> Result<()> {
 	let mut machine = ScriptEngine::new();
+	for flag in &args.flags {
+		machine.set_flag(flag);
+	}
+	for param in &args.params {
+		let (name, value) = param.split_once('=').with_context(|| format!("Expected NAME=VALUE for --set, not {}", param))?;
+		let name = name.trim();
+		machine.set_param(name, value).with_context(|| format!("Invalid value for --set {}", name))?;
+	}
+	if let Some(seed) = args.seed {
+		machine.set_seed(seed);
+	}
+	machine.set_max_call_depth(args.max_call_depth);
+	if let Some(steps) = args.max_steps {
+		machine.set_max_steps(steps);
+	}
+	if args.pause_operations {
+		machine.set_pause_operations(true);
+	}
+	if args.feed_scale <= 0.0 || args.rpm_scale <= 0.0 {
+		bail!("--feed-scale and --rpm-scale must be positive");
+	}
+	machine.set_speed_scale(args.feed_scale, args.rpm_scale);
 	machine.write_header();
 	machine.run(BUILTIN_MATERIALS, args.verbose)?;
-	machine.run_file(args.input, args.verbose)?;
+	if args.progress {
+		machine.set_progress_callback(|progress| {
+			eprint!("\r\x1b[K[{:3.0}%] {}", progress.fraction * 100.0, progress.builtin);
+		});
+	}
+
+	let result = (|| {
+		machine.run_file(&args.input, args.verbose)?;
+
+		let mut output_file = File::create(&args.output).with_context(|| format!("Failed to create file: {}", args.output.display()))?;
+		let writer = BufWriter::new(&mut output_file);
+		machine.finish(writer)
+	})();
+
+	if args.progress {
+		eprintln!();
+	}
+
+	// Warnings often explain an error, so they are shown either way
+	for warning in machine.warnings() {
+		eprintln!("Warning: {}", warning);
+	}
+	result?;
+
+	for name in machine.undeclared_params() {
+		eprintln!("Warning: --set {} was given, but the script has no param named {}", name, name);
+	}
 
-	let mut output_file = File::create(&args.output).with_context(|| format!("Failed to create file: {}", args.output.display()))?;
-	let writer = BufWriter::new(&mut output_file);
-	machine.finish(writer)?;
+	if args.timings {
+		let timings = machine.timings();
+		eprintln!("Parse:    {:?}", timings.parse);
+		eprintln!("Eval:     {:?}", timings.eval);
+		eprintln!("Builtins: {:?}", timings.builtins);
+		eprintln!("Write:    {:?}", timings.write);
+	}
 
 	Ok(())
 }
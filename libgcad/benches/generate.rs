@@ -0,0 +1,85 @@
+//! Times parsing, evaluating and postprocessing a few representative scripts, reporting the mean of each phase from
+//! ScriptEngine::timings.
+use std::time::Duration;
+
+use libgcad::{ScriptEngine, Timings, BUILTIN_MATERIALS};
+
+
+const ITERATIONS: u32 = 20;
+
+const HOLES: &str = "
+cutter_diameter(6.35mm)
+material('BALTIC_BIRCH_PLYWOOD')
+
+for y in linspace(1.5in, 30in, 8) {
+	for x in linspace(3/4in, 3.25in, 4) {
+		comment('Counterbore')
+		circle_pocket(x, y, radius=6.35mm, depth=3mm)
+		comment('Threaded insert hole')
+		circle_pocket(x, y, radius=4.75mm, depth=12mm)
+	}
+}
+";
+
+const SCRIPT_HEAVY: &str = "
+fn fib(n) {
+	if n < 2 {
+		return n
+	}
+	return fib(n - 1) + fib(n - 2)
+}
+
+total = 0
+for i in 0..2000 {
+	total += sqrt(i) * 2mm
+}
+fn square(x) {
+	return x * x
+}
+squares = sort(map(0..500, square))
+big = fib(16)
+";
+
+const MARLIN: &str = "
+cutter_diameter(3.175mm)
+material('BALTIC_BIRCH_PLYWOOD')
+machine(max_feed=2000, rapid_feed=3000)
+postprocessor(flavor='marlin', rapids='feed', vacuum='fan', operations='comments', feed_scale=0.5)
+
+for x in linspace(10mm, 200mm, 20) {
+	circle_pocket(x, 20mm, radius=4mm, depth=6mm)
+	groove_pocket(x, 60mm, x + 5mm, 80mm, depth=3mm)
+}
+";
+
+
+fn main() {
+	for (name, source) in [("holes", HOLES), ("script_heavy", SCRIPT_HEAVY), ("marlin", MARLIN)] {
+		let mut engine = ScriptEngine::new();
+		engine.run(BUILTIN_MATERIALS, false).unwrap();
+		let mut total = Timings::default();
+
+		for _ in 0..ITERATIONS {
+			engine.reset();
+			engine.write_header();
+			engine.run(source, false).unwrap();
+			engine.finish(std::io::sink()).unwrap();
+
+			let timings = engine.timings();
+			total.parse += timings.parse;
+			total.eval += timings.eval;
+			total.builtins += timings.builtins;
+			total.write += timings.write;
+		}
+
+		let mean = |duration: Duration| duration / ITERATIONS;
+		println!(
+			"{:<14} parse {:>10.2?}  eval {:>10.2?}  builtins {:>10.2?}  postprocess {:>10.2?}",
+			name,
+			mean(total.parse),
+			mean(total.eval),
+			mean(total.builtins),
+			mean(total.write)
+		);
+	}
+}
@@ -0,0 +1,34 @@
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Arc,
+};
+
+use anyhow::{bail, Result};
+
+
+/// Shared flag that lets another thread abort a running script.  Clones refer to the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn cancel(&self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::Relaxed)
+	}
+
+	/// Errors if the token has been cancelled, for checking inside long loops.
+	pub fn check(&self) -> Result<()> {
+		if self.is_cancelled() {
+			bail!("Cancelled");
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,713 @@
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+use pest::{
+	iterators::{Pair, Pairs},
+	pratt_parser::{Assoc, Op, PrattParser},
+};
+
+use super::Rule;
+use crate::{
+	numbers::{InnerValue, Number, Unit},
+	value::ScriptValue,
+};
+
+
+/// Text of a script, and the file it came from if any.
+#[derive(Debug)]
+pub struct Source {
+	pub text: String,
+	pub path: Option<String>,
+}
+
+
+/// Location of a node in the script, kept so that runtime errors can point at the source like parse errors do.
+#[derive(Clone, Debug)]
+pub struct Span {
+	source: Rc<Source>,
+	start: usize,
+	end: usize,
+}
+
+impl Span {
+	fn new(span: pest::Span, source: &Rc<Source>) -> Span {
+		Span {
+			source: source.clone(),
+			start: span.start(),
+			end: span.end(),
+		}
+	}
+
+	fn to(&self, other: &Span) -> Span {
+		Span {
+			source: self.source.clone(),
+			start: self.start.min(other.start),
+			end: self.end.max(other.end),
+		}
+	}
+
+	/// Where the span starts, as path:line:column, or line:column for a script without a path.
+	pub fn location(&self) -> String {
+		let (line, column) = pest::Position::new(&self.source.text, self.start)
+			.expect("Span out of range of its source")
+			.line_col();
+
+		match &self.source.path {
+			Some(path) => format!("{}:{}:{}", path, line, column),
+			None => format!("{}:{}", line, column),
+		}
+	}
+
+	pub fn error(&self, message: impl Into<String>) -> anyhow::Error {
+		let span = pest::Span::new(&self.source.text, self.start, self.end).expect("Span out of range of its source");
+		let error = pest::error::Error::new_from_span(pest::error::ErrorVariant::<()>::CustomError { message: message.into() }, span);
+
+		match &self.source.path {
+			Some(path) => error.with_path(path).into(),
+			None => error.into(),
+		}
+	}
+
+	/// Points an error at this span, unless it already points somewhere in a script, such as inside a function called from
+	/// here.
+	pub fn locate(&self, error: anyhow::Error) -> anyhow::Error {
+		match error.downcast_ref::<pest::error::Error<()>>() {
+			Some(_) => error,
+			None => self.error(error.to_string()),
+		}
+	}
+}
+
+
+pub enum Stmt {
+	Expr(Expr),
+	For {
+		variables: Vec<String>,
+		iterable: Expr,
+		body: Vec<Stmt>,
+	},
+	Repeat {
+		count: Expr,
+		body: Vec<Stmt>,
+	},
+	/// An else if is stored as an else block holding a single If.
+	If {
+		condition: Expr,
+		body: Vec<Stmt>,
+		else_body: Option<Vec<Stmt>>,
+	},
+	Match {
+		value: Expr,
+		arms: Vec<MatchArm>,
+	},
+	Break(Span),
+	Continue(Span),
+	/// Ends the current function with a value (null if there is none), or the current file at the top level.
+	Return(Option<Expr>),
+	Let {
+		variable: String,
+		value: Expr,
+		constant: bool,
+		span: Span,
+	},
+	/// A constant that takes its value from ScriptEngine::set_param when one was given, and from default otherwise.
+	Param {
+		variable: String,
+		default: Expr,
+		span: Span,
+	},
+	Global {
+		variable: String,
+		value: Expr,
+		span: Span,
+	},
+	Function(Rc<Function>),
+	/// Assigns each item of a list to the variable in the same position.
+	Destructure {
+		variables: Vec<String>,
+		value: Expr,
+		span: Span,
+	},
+	/// Runs another script file, found relative to the one including it.
+	Include {
+		path: String,
+		span: Span,
+	},
+}
+
+/// A function defined by the script.
+pub struct Function {
+	pub name: String,
+	/// Names and default values of the parameters.
+	pub params: Vec<(String, Option<Expr>)>,
+	pub body: Vec<Stmt>,
+}
+
+pub struct MatchArm {
+	/// Empty for the _ arm, which matches anything.
+	pub patterns: Vec<Expr>,
+	pub body: Vec<Stmt>,
+}
+
+pub struct Expr {
+	pub kind: ExprKind,
+	pub span: Span,
+}
+
+pub enum ExprKind {
+	Literal(ScriptValue),
+	Variable(String),
+	/// Reads a global even if a local hides it, for compound assignments to globals.
+	GlobalVariable(String),
+	/// Dotted name like stock.width, which is always provided by the engine.
+	Member(String),
+	Assign(String, Box<Expr>),
+	List(Vec<Expr>),
+	Call {
+		name: String,
+		name_span: Span,
+		args: Vec<Expr>,
+		named_args: Vec<(String, Expr)>,
+	},
+	Unary(UnaryOp, Box<Expr>),
+	Binary(BinaryOp, Box<Expr>, Box<Expr>),
+	Index(Box<Expr>, Box<Expr>),
+	Range {
+		start: Box<Expr>,
+		stop: Box<Expr>,
+		step: Option<Box<Expr>>,
+		inclusive: bool,
+	},
+}
+
+#[derive(Clone, Copy)]
+pub enum UnaryOp {
+	Negate,
+	Not,
+	Factorial,
+}
+
+#[derive(Clone, Copy)]
+pub enum BinaryOp {
+	Add,
+	Subtract,
+	Multiply,
+	Divide,
+	Power,
+	Equal,
+	NotEqual,
+	Less,
+	LessEqual,
+	Greater,
+	GreaterEqual,
+	And,
+	Or,
+	Coalesce,
+}
+
+
+/// Turns the parse tree of a program into statements that can be executed repeatedly without going back to pest.
+pub fn compile(pairs: Pairs<Rule>, source: &Rc<Source>) -> Result<Vec<Stmt>> {
+	let compiler = Compiler::new(source);
+
+	pairs.filter(|pair| pair.as_rule() != Rule::EOI).map(|pair| compiler.stmt(pair)).collect()
+}
+
+
+/// Compiles the parse tree of a standaloneExpr.
+pub fn compile_expr(mut pairs: Pairs<Rule>, source: &Rc<Source>) -> Result<Expr> {
+	Compiler::new(source).expr(pairs.next().unwrap())
+}
+
+
+struct Compiler {
+	source: Rc<Source>,
+	pratt: PrattParser<Rule>,
+}
+
+impl Compiler {
+	fn new(source: &Rc<Source>) -> Compiler {
+		Compiler {
+			source: source.clone(),
+			pratt: PrattParser::new()
+				.op(Op::infix(Rule::coalesce, Assoc::Right))
+				.op(Op::infix(Rule::or, Assoc::Left))
+				.op(Op::infix(Rule::and, Assoc::Left))
+				.op(Op::prefix(Rule::not))
+				.op(Op::infix(Rule::equal, Assoc::Left) | Op::infix(Rule::notEqual, Assoc::Left))
+				.op(Op::infix(Rule::less, Assoc::Left)
+					| Op::infix(Rule::lessEqual, Assoc::Left)
+					| Op::infix(Rule::greater, Assoc::Left)
+					| Op::infix(Rule::greaterEqual, Assoc::Left))
+				.op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::subtract, Assoc::Left))
+				.op(Op::infix(Rule::multiply, Assoc::Left) | Op::infix(Rule::divide, Assoc::Left))
+				// Negation binds looser than ^, so -x^2 is -(x^2)
+				.op(Op::prefix(Rule::negate))
+				.op(Op::infix(Rule::power, Assoc::Right))
+				.op(Op::postfix(Rule::factorial) | Op::postfix(Rule::index)),
+		}
+	}
+
+	fn span(&self, pair: &Pair<Rule>) -> Span {
+		Span::new(pair.as_span(), &self.source)
+	}
+
+	fn stmt(&self, pair: Pair<Rule>) -> Result<Stmt> {
+		Ok(match pair.as_rule() {
+			Rule::exprStatement => Stmt::Expr(self.expr(pair.into_inner().next().unwrap())?),
+			Rule::forLoop => {
+				let mut pair = pair.into_inner();
+				let body = self.block(pair.next_back().unwrap())?;
+				let iterable = self.expr(pair.next_back().unwrap())?;
+
+				Stmt::For {
+					variables: pair.map(|ident| ident.as_str().to_string()).collect(),
+					iterable,
+					body,
+				}
+			},
+			Rule::repeatLoop => {
+				let mut pair = pair.into_inner().skip(1);
+
+				Stmt::Repeat {
+					count: self.expr(pair.next().unwrap())?,
+					body: self.block(pair.next().unwrap())?,
+				}
+			},
+			Rule::ifStatement => {
+				let mut pair = pair.into_inner();
+				let condition = self.expr(pair.next().unwrap())?;
+				let body = self.block(pair.next().unwrap())?;
+
+				if let ExprKind::Assign(..) = condition.kind {
+					return Err(condition.span.error("This assigns instead of comparing; use == to compare values"));
+				}
+				let else_body = match pair.next() {
+					Some(else_branch) if else_branch.as_rule() == Rule::ifStatement => Some(vec![self.stmt(else_branch)?]),
+					Some(else_branch) => Some(self.block(else_branch)?),
+					None => None,
+				};
+
+				Stmt::If { condition, body, else_body }
+			},
+			Rule::matchStatement => {
+				let mut pair = pair.into_inner().skip(1);
+				let value = self.expr(pair.next().unwrap())?;
+				let arms = pair
+					.map(|arm| {
+						let mut patterns = arm.into_inner().collect::<Vec<_>>();
+						let body = patterns.pop().unwrap();
+						let body = match body.as_rule() {
+							Rule::block => self.block(body)?,
+							_ => vec![Stmt::Expr(self.expr(body)?)],
+						};
+						let patterns = patterns
+							.into_iter()
+							.filter(|pattern| pattern.as_rule() != Rule::wildcard)
+							.map(|pattern| self.expr(pattern))
+							.collect::<Result<_>>()?;
+
+						Ok(MatchArm { patterns, body })
+					})
+					.collect::<Result<_>>()?;
+
+				Stmt::Match { value, arms }
+			},
+			Rule::breakStatement => Stmt::Break(self.span(&pair)),
+			Rule::continueStatement => Stmt::Continue(self.span(&pair)),
+			Rule::returnStatement => Stmt::Return(pair.into_inner().nth(1).map(|value| self.expr(value)).transpose()?),
+			Rule::letStatement => {
+				let span = self.span(&pair);
+				let mut pair = pair.into_inner();
+
+				Stmt::Let {
+					constant: pair.next().unwrap().as_rule() == Rule::constKeyword,
+					variable: pair.next().unwrap().as_str().to_string(),
+					value: self.expr(pair.next().unwrap())?,
+					span,
+				}
+			},
+			Rule::paramStatement => {
+				let span = self.span(&pair);
+				let mut pair = pair.into_inner().skip(1);
+
+				Stmt::Param {
+					variable: pair.next().unwrap().as_str().to_string(),
+					default: self.expr(pair.next().unwrap())?,
+					span,
+				}
+			},
+			Rule::globalStatement => {
+				let span = self.span(&pair);
+				let mut pair = pair.into_inner().skip(1);
+				let ident = pair.next().unwrap();
+				let variable = ident.as_str().to_string();
+				let global = Expr {
+					kind: ExprKind::GlobalVariable(variable.clone()),
+					span: self.span(&ident),
+				};
+
+				Stmt::Global {
+					value: self.assigned_value(global, pair.next().unwrap(), self.expr(pair.next().unwrap())?, &span),
+					variable,
+					span,
+				}
+			},
+			Rule::destructure => {
+				let span = self.span(&pair);
+				let mut pair = pair.into_inner();
+				let value = self.expr(pair.next_back().unwrap())?;
+
+				Stmt::Destructure {
+					variables: pair.map(|ident| ident.as_str().to_string()).collect(),
+					value,
+					span,
+				}
+			},
+			Rule::functionDef => {
+				let mut pair = pair.into_inner();
+				let name = pair.nth(1).unwrap().as_str().to_string();
+				let body = self.block(pair.next_back().unwrap())?;
+				let params = pair
+					.map(|param| {
+						let mut param = param.into_inner();
+						let name = param.next().unwrap().as_str().to_string();
+
+						Ok((name, param.next().map(|default| self.expr(default)).transpose()?))
+					})
+					.collect::<Result<_>>()?;
+
+				Stmt::Function(Rc::new(Function { name, params, body }))
+			},
+			Rule::includeStatement => Stmt::Include {
+				span: self.span(&pair),
+				path: self.string(pair.into_inner().next().unwrap())?,
+			},
+			rule => bail!("Unexpected rule: {:?}", rule),
+		})
+	}
+
+	fn block(&self, pair: Pair<Rule>) -> Result<Vec<Stmt>> {
+		pair.into_inner().map(|pair| self.stmt(pair)).collect()
+	}
+
+	/// The value stored by an assignment.  Compound assignments are sugar for applying the operator to the variable, e.g.
+	/// x += 1 is x = x + 1.
+	fn assigned_value(&self, variable: Expr, op: Pair<Rule>, value: Expr, span: &Span) -> Expr {
+		let op = match op.as_str() {
+			"=" => return value,
+			"+=" => BinaryOp::Add,
+			"-=" => BinaryOp::Subtract,
+			"*=" => BinaryOp::Multiply,
+			"/=" => BinaryOp::Divide,
+			_ => unreachable!(),
+		};
+
+		Expr {
+			span: span.clone(),
+			kind: ExprKind::Binary(op, Box::new(variable), Box::new(value)),
+		}
+	}
+
+	fn string(&self, pair: Pair<Rule>) -> Result<String> {
+		let str = pair.as_str();
+
+		let body = &str[1..str.len() - 1];
+
+		match str.chars().next() {
+			Some('"') => unescape(body).map_err(|e| self.span(&pair).error(e)),
+			_ => Ok(body.replace("''", "'")),
+		}
+	}
+
+	fn expr(&self, pair: Pair<Rule>) -> Result<Expr> {
+		let span = self.span(&pair);
+
+		let kind = match pair.as_rule() {
+			Rule::expr => return self.expr(pair.into_inner().next().unwrap()),
+			Rule::mathExpr => return self.math_expr(pair.into_inner()),
+			Rule::rangeExpr => {
+				let mut pair = pair.into_inner();
+				let start = self.expr(pair.next().unwrap())?;
+				let Some(op) = pair.next() else {
+					return Ok(start);
+				};
+
+				ExprKind::Range {
+					start: Box::new(start),
+					inclusive: op.as_str() == "..=",
+					stop: Box::new(self.expr(pair.next().unwrap())?),
+					step: pair.nth(1).map(|step| self.expr(step)).transpose()?.map(Box::new),
+				}
+			},
+			Rule::assign => {
+				let mut pair = pair.into_inner();
+				let ident = pair.next().unwrap();
+				let variable = Expr {
+					kind: ExprKind::Variable(ident.as_str().to_string()),
+					span: self.span(&ident),
+				};
+				let value = self.assigned_value(variable, pair.next().unwrap(), self.expr(pair.next().unwrap())?, &span);
+
+				ExprKind::Assign(ident.as_str().to_string(), Box::new(value))
+			},
+			Rule::list => ExprKind::List(pair.into_inner().map(|item| self.expr(item)).collect::<Result<_>>()?),
+			Rule::boolean => ExprKind::Literal(ScriptValue::Bool(pair.as_str() == "true")),
+			Rule::null => ExprKind::Literal(ScriptValue::Null),
+			Rule::string => ExprKind::Literal(ScriptValue::String(self.string(pair)?)),
+			Rule::unitless_number | Rule::unit_number => {
+				let mut pair = pair.into_inner();
+				let value = pair.next().unwrap();
+				let value = match value.as_rule() {
+					Rule::integer => value.as_str().parse().ok().map(InnerValue::Integer),
+					Rule::decimal => value.as_str().parse().ok().map(InnerValue::Float),
+					_ => unreachable!(),
+				};
+				let unit = match pair.next() {
+					Some(unit) => unit.as_str().parse().expect("Could not parse unit"),
+					None => Unit::None,
+				};
+
+				ExprKind::Literal(ScriptValue::Number(Number {
+					value: value.ok_or_else(|| span.error("Invalid number"))?,
+					unit,
+				}))
+			},
+			Rule::dashed_fraction => {
+				let (whole, fraction) = pair.as_str().split_once('-').unwrap();
+
+				return Err(span.error(format!(
+					"{} is ambiguous; write {} {} for a mixed number, or {} - {} to subtract",
+					pair.as_str(),
+					whole,
+					fraction,
+					whole,
+					fraction
+				)));
+			},
+			Rule::fraction_number => {
+				let parts = pair.into_inner().map(|part| part.as_str().parse::<i64>()).collect::<Result<Vec<_>, _>>();
+				let (whole, numerator, denominator) = match parts.as_deref() {
+					Ok(&[numerator, denominator]) => (0, numerator, denominator),
+					Ok(&[whole, numerator, denominator]) => (whole, numerator, denominator),
+					_ => return Err(span.error("Invalid number")),
+				};
+
+				if denominator == 0 {
+					return Err(span.error("The denominator of a fraction must not be zero"));
+				}
+
+				ExprKind::Literal(ScriptValue::Number(Number {
+					value: InnerValue::Float(whole as f64 + numerator as f64 / denominator as f64),
+					unit: Unit::IN,
+				}))
+			},
+			Rule::funcCall => {
+				let mut pair = pair.into_inner();
+				let ident = pair.next().unwrap();
+				let mut args = Vec::new();
+				let mut named_args = Vec::new();
+
+				for param in pair.flat_map(|params| params.into_inner()) {
+					match param.as_rule() {
+						Rule::positionalParam => args.push(self.expr(param.into_inner().next().unwrap())?),
+						Rule::namedParam => {
+							let mut param = param.into_inner();
+							let name = param.next().unwrap().as_str().to_string();
+							named_args.push((name, self.expr(param.next().unwrap())?));
+						},
+						_ => unreachable!(),
+					}
+				}
+
+				ExprKind::Call {
+					name: ident.as_str().to_string(),
+					name_span: self.span(&ident),
+					args,
+					named_args,
+				}
+			},
+			Rule::ident => ExprKind::Variable(pair.as_str().to_string()),
+			Rule::member => ExprKind::Member(pair.as_str().to_string()),
+			rule => bail!("Unexpected expr: {:?}", rule),
+		};
+
+		Ok(Expr { kind, span })
+	}
+
+	fn math_expr(&self, pairs: Pairs<Rule>) -> Result<Expr> {
+		self.pratt
+			.map_primary(|primary| self.expr(primary))
+			.map_prefix(|op, rhs| {
+				let rhs = rhs?;
+				let op_kind = match op.as_rule() {
+					Rule::negate => UnaryOp::Negate,
+					Rule::not => UnaryOp::Not,
+					_ => unreachable!(),
+				};
+
+				Ok(Expr {
+					span: self.span(&op).to(&rhs.span),
+					kind: ExprKind::Unary(op_kind, Box::new(rhs)),
+				})
+			})
+			.map_postfix(|lhs, op| {
+				let lhs = lhs?;
+				let span = lhs.span.to(&self.span(&op));
+
+				let kind = match op.as_rule() {
+					Rule::factorial => ExprKind::Unary(UnaryOp::Factorial, Box::new(lhs)),
+					Rule::index => ExprKind::Index(Box::new(lhs), Box::new(self.expr(op.into_inner().next().unwrap())?)),
+					_ => unreachable!(),
+				};
+
+				Ok(Expr { kind, span })
+			})
+			.map_infix(|lhs, op, rhs| {
+				let lhs = lhs?;
+				let rhs = rhs?;
+				let op = match op.as_rule() {
+					Rule::add => BinaryOp::Add,
+					Rule::subtract => BinaryOp::Subtract,
+					Rule::multiply => BinaryOp::Multiply,
+					Rule::divide => BinaryOp::Divide,
+					Rule::power => BinaryOp::Power,
+					Rule::equal => BinaryOp::Equal,
+					Rule::notEqual => BinaryOp::NotEqual,
+					Rule::less => BinaryOp::Less,
+					Rule::lessEqual => BinaryOp::LessEqual,
+					Rule::greater => BinaryOp::Greater,
+					Rule::greaterEqual => BinaryOp::GreaterEqual,
+					Rule::and => BinaryOp::And,
+					Rule::or => BinaryOp::Or,
+					Rule::coalesce => BinaryOp::Coalesce,
+					_ => unreachable!(),
+				};
+
+				Ok(Expr {
+					span: lhs.span.to(&rhs.span),
+					kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)),
+				})
+			})
+			.parse(pairs)
+	}
+}
+
+
+/// Resolves the escapes in the body of a double quoted string literal: \n, \r, \t, \0, \\, \', \" and \u{XXXX}.  Other
+/// backslashes are kept as they are.
+fn unescape(str: &str) -> Result<String, String> {
+	let mut result = String::with_capacity(str.len());
+	let mut chars = str.chars();
+
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' => match chars.next() {
+				Some('n') => result.push('\n'),
+				Some('r') => result.push('\r'),
+				Some('t') => result.push('\t'),
+				Some('0') => result.push('\0'),
+				Some(c @ ('\\' | '\'' | '"')) => result.push(c),
+				Some('u') => {
+					let (c, rest) = chars
+						.as_str()
+						.strip_prefix('{')
+						.and_then(|rest| rest.split_once('}'))
+						.filter(|(hex, _)| (1..=6).contains(&hex.len()))
+						.and_then(|(hex, rest)| Some((char::from_u32(u32::from_str_radix(hex, 16).ok()?)?, rest)))
+						.ok_or("Invalid unicode escape; expected \\u{XXXX} with 1 to 6 hex digits")?;
+
+					result.push(c);
+					chars = rest.chars();
+				},
+				Some(c) => {
+					result.push('\\');
+					result.push(c);
+				},
+				None => result.push('\\'),
+			},
+			c => result.push(c),
+		}
+	}
+
+	Ok(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use pest::Parser;
+
+	use super::*;
+	use crate::engine::ScriptParser;
+
+	fn compile_source(text: &str) -> Vec<Stmt> {
+		let source = Rc::new(Source {
+			text: text.to_string(),
+			path: Some("test.gcad".to_string()),
+		});
+
+		compile(ScriptParser::parse(Rule::program, &source.text).unwrap(), &source).unwrap()
+	}
+
+	fn text(span: &Span) -> &str {
+		&span.source.text[span.start..span.end]
+	}
+
+	#[test]
+	fn spans() {
+		let program = compile_source("x = 1\n\ny = -x + foo(2, size=3mm) * 2\n");
+		let Stmt::Expr(Expr {
+			kind: ExprKind::Assign(_, value),
+			span,
+		}) = &program[1]
+		else {
+			panic!("expected an assignment");
+		};
+		let ExprKind::Binary(BinaryOp::Add, lhs, rhs) = &value.kind else {
+			panic!("expected an addition");
+		};
+		let ExprKind::Binary(BinaryOp::Multiply, call, _) = &rhs.kind else {
+			panic!("expected a multiplication");
+		};
+		let ExprKind::Call { name_span, named_args, .. } = &call.kind else {
+			panic!("expected a call");
+		};
+
+		assert_eq!(text(span), "y = -x + foo(2, size=3mm) * 2");
+		assert_eq!(text(&value.span), "-x + foo(2, size=3mm) * 2");
+		assert_eq!(text(&lhs.span), "-x");
+		assert_eq!(text(&rhs.span), "foo(2, size=3mm) * 2");
+		assert_eq!(text(&call.span), "foo(2, size=3mm)");
+		assert_eq!(text(name_span), "foo");
+		assert_eq!(text(&named_args[0].1.span), "3mm");
+		assert_eq!(call.span.location(), "test.gcad:3:10");
+	}
+
+	#[test]
+	fn compile_errors_point_at_the_node() {
+		let source = Rc::new(Source {
+			text: "x = 1\ny = 3/0in\n".to_string(),
+			path: None,
+		});
+		let pairs = ScriptParser::parse(Rule::program, &source.text).unwrap();
+		let error = compile(pairs, &source).err().unwrap().to_string();
+
+		assert!(error.contains("--> 2:5"), "{}", error);
+		assert!(error.contains("denominator"), "{}", error);
+	}
+
+	#[test]
+	fn unescape_double_quoted() {
+		assert_eq!(unescape(r#"a\tb\n\\ \"q\" \'"#).unwrap(), "a\tb\n\\ \"q\" '");
+		assert_eq!(unescape(r"\u{e9}\u{1F600}").unwrap(), "é😀");
+		assert!(unescape(r"\u{110000}").is_err());
+		assert!(unescape(r"\u{}").is_err());
+	}
+
+	#[test]
+	fn unescape_keeps_unknown_escapes() {
+		assert_eq!(unescape(r"C:\temp\x").unwrap(), "C:\temp\\x");
+		assert_eq!(unescape(r"\d+").unwrap(), r"\d+");
+	}
+}
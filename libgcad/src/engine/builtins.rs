@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{cmp::Ordering, collections::HashMap};
 
 use gcad_proc_macros::ffi_func;
 
@@ -6,34 +6,130 @@ use anyhow::{anyhow, bail, Result};
 use nalgebra::{Matrix3, Vector2};
 
 use crate::{
-	numbers::{Number, Unit},
+	gcode::{AxisMap, Drain, Flavor, Stock},
+	image::GrayImage,
+	numbers::{InnerValue, Number, Unit},
+	qr::QrCode,
+	random::Rng,
 	value::ScriptValue,
 };
 
-use super::{Material, ScriptEngine};
+use super::{ast::Span, Material, ScriptEngine};
 
+// Builtin signatures mirror their script arguments, which can be numerous
+#[allow(clippy::too_many_arguments)]
 impl ScriptEngine {
-	pub fn call_builtin(&mut self, ident: &str, args: &[ScriptValue], nargs: &HashMap<String, ScriptValue>) -> Result<Option<ScriptValue>> {
+	pub fn call_builtin(&mut self, ident: &str, args: Vec<ScriptValue>, nargs: HashMap<String, ScriptValue>) -> Result<Option<ScriptValue>> {
 		Ok(match ident {
 			"rpm" => Some(self.builtin_rpm_ffi(args, nargs)?),
 			"material" => Some(self.builtin_material_ffi(args, nargs)?),
 			"cutter_diameter" => Some(self.builtin_cutter_diameter_ffi(args, nargs)?),
 			"contour_line" => Some(self.builtin_contour_line_ffi(args, nargs)?),
 			"define_material" => Some(self.builtin_define_material_ffi(args, nargs)?),
+			"machine" => Some(self.builtin_machine_ffi(args, nargs)?),
+			"postprocessor" => Some(self.builtin_postprocessor_ffi(args, nargs)?),
 			"drill" => Some(self.builtin_drill_ffi(args, nargs)?),
 			"circle_pocket" => Some(self.builtin_circle_pocket_ffi(args, nargs)?),
 			"groove_pocket" => Some(self.builtin_groove_pocket_ffi(args, nargs)?),
+			"halftone_drill" => Some(self.builtin_halftone_drill_ffi(args, nargs)?),
+			"qr_code" => Some(self.builtin_qr_code_ffi(args, nargs)?),
 			"comment" => Some(self.builtin_comment_ffi(args, nargs)?),
+			"assert" => Some(self.builtin_assert_ffi(args, nargs)?),
+			"error" => Some(self.builtin_error_ffi(args, nargs)?),
+			"warn" => Some(self.builtin_warn_ffi(args, nargs)?),
+			"print" => Some(print(args, nargs)?),
+			"format" => Some(format(args, nargs)?),
+			"stock" => Some(self.builtin_stock_ffi(args, nargs)?),
+			"clearance" => Some(self.builtin_clearance_ffi(args, nargs)?),
+			"pause" => Some(self.builtin_pause_ffi(args, nargs)?),
+			"flip" => Some(self.builtin_flip_ffi(args, nargs)?),
+			"from_edge" => Some(self.builtin_from_edge_ffi(args, nargs)?),
+			"centered" => Some(self.builtin_centered_ffi(args, nargs)?),
+			"center_rect" => Some(self.builtin_center_rect_ffi(args, nargs)?),
+			"rand" | "random" => Some(self.builtin_rand_ffi(args, nargs)?),
+			"seed" => Some(self.builtin_seed_ffi(args, nargs)?),
+			"flag" => Some(self.builtin_flag_ffi(args, nargs)?),
 			"linspace" => Some(self.builtin_linspace_ffi(args, nargs)?),
+			"len" => Some(self.builtin_len_ffi(args, nargs)?),
+			"append" => Some(self.builtin_append_ffi(args, nargs)?),
+			"enumerate" => Some(self.builtin_enumerate_ffi(args, nargs)?),
+			"range" => Some(self.builtin_range_ffi(args, nargs)?),
+			"map" => Some(self.builtin_map_ffi(args, nargs)?),
+			"filter" => Some(self.builtin_filter_ffi(args, nargs)?),
+			"sort" => Some(self.builtin_sort_ffi(args, nargs)?),
+			"min_of" => Some(self.builtin_min_of_ffi(args, nargs)?),
+			"max_of" => Some(self.builtin_max_of_ffi(args, nargs)?),
+			"reverse" => Some(self.builtin_reverse_ffi(args, nargs)?),
+			"is_clockwise" => Some(self.builtin_is_clockwise_ffi(args, nargs)?),
+			"sin" => Some(self.builtin_sin_ffi(args, nargs)?),
+			"cos" => Some(self.builtin_cos_ffi(args, nargs)?),
+			"tan" => Some(self.builtin_tan_ffi(args, nargs)?),
+			"atan2" => Some(self.builtin_atan2_ffi(args, nargs)?),
+			"polar" => Some(self.builtin_polar_ffi(args, nargs)?),
+			"sqrt" => Some(self.builtin_sqrt_ffi(args, nargs)?),
+			"hypot" => Some(self.builtin_hypot_ffi(args, nargs)?),
+			"floor" => Some(self.builtin_floor_ffi(args, nargs)?),
+			"ceil" => Some(self.builtin_ceil_ffi(args, nargs)?),
+			"round" => Some(self.builtin_round_ffi(args, nargs)?),
+			"abs" => Some(self.builtin_abs_ffi(args, nargs)?),
+			"min" => Some(extreme("min", args, nargs, Ordering::Less)?),
+			"max" => Some(extreme("max", args, nargs, Ordering::Greater)?),
+			"clamp" => Some(self.builtin_clamp_ffi(args, nargs)?),
+			"typeof" => Some(self.builtin_typeof_ffi(args, nargs)?),
+			"is_number" => Some(self.builtin_is_number_ffi(args, nargs)?),
+			"is_string" => Some(self.builtin_is_string_ffi(args, nargs)?),
+			"is_bool" => Some(self.builtin_is_bool_ffi(args, nargs)?),
+			"is_list" => Some(self.builtin_is_list_ffi(args, nargs)?),
+			"is_null" => Some(self.builtin_is_null_ffi(args, nargs)?),
+			"is_defined" => Some(self.builtin_is_defined_ffi(args, nargs)?),
 			"scale" => Some(self.builtin_scale_ffi(args, nargs)?),
 			"translate" => Some(self.builtin_translate_ffi(args, nargs)?),
+			"rotate" => Some(self.builtin_rotate_ffi(args, nargs)?),
+			_ => None,
+		})
+	}
+
+	/// Values provided by the engine that scripts can read like variables.  Script variables take precedence.
+	pub fn builtin_variable(&self, ident: &str) -> Result<Option<ScriptValue>> {
+		let mm = |value: Option<f64>| -> Result<Option<ScriptValue>> {
+			let value = value.ok_or_else(|| anyhow!("{} is not set", ident))?;
+			Ok(Some(ScriptValue::Number(Number::from_float_and_unit(value, "mm"))))
+		};
+		let number = |value: Option<f64>| -> Result<Option<ScriptValue>> {
+			let value = value.ok_or_else(|| anyhow!("{} is not set", ident))?;
+			Ok(Some(ScriptValue::Number(Number::from_float(value))))
+		};
+		let stock = self.gcode.stock;
+		let machine = &self.gcode.machine;
+
+		Ok(match ident {
+			"stock.width" => mm(stock.map(|stock| stock.width))?,
+			"stock.height" => mm(stock.map(|stock| stock.height))?,
+			"stock.thickness" => mm(stock.and_then(|stock| stock.thickness))?,
+			"stock.top" => mm(Some(self.gcode.stock_top))?,
+			"machine.max_x" => mm(machine.max_x)?,
+			"machine.max_y" => mm(machine.max_y)?,
+			"machine.max_feed" => number(machine.max_feed)?,
+			"machine.max_z_feed" => number(machine.max_z_feed)?,
+			"machine.rapid_feed" => number(machine.rapid_feed)?,
+			"pi" => number(Some(std::f64::consts::PI))?,
+			// Depth that cuts all the way through the stock
+			"through" => {
+				let thickness = self
+					.gcode
+					.stock
+					.and_then(|stock| stock.thickness)
+					.ok_or_else(|| anyhow!("through requires the stock thickness"))?;
+
+				Some(ScriptValue::Number(Number::from_float_and_unit(thickness, "mm")))
+			},
 			_ => None,
 		})
 	}
 
 	#[ffi_func]
 	fn builtin_rpm(&mut self, rpm: Number) -> Result<ScriptValue> {
-		let rpm = rpm.as_float().ok_or(anyhow!("rpm: argument 0 must be a number"))?;
+		let rpm = rpm.as_float().ok_or_else(|| anyhow!("rpm: argument 0 must be a number"))?;
 
 		self.gcode.set_rpm(rpm);
 
@@ -58,8 +154,8 @@ impl ScriptEngine {
 
 	#[ffi_func]
 	fn builtin_cutter_diameter(&mut self, diameter: Number) -> Result<ScriptValue> {
-		if diameter.unit == Unit::None {
-			bail!("diameter must have a unit");
+		if !diameter.unit.is_length() {
+			bail!("diameter must have a length unit");
 		}
 
 		self.gcode.cutter_diameter = diameter.convert_unit(Unit::MM).into();
@@ -74,12 +170,23 @@ impl ScriptEngine {
 		y1: Number,
 		x2: Option<Number>,
 		y2: Option<Number>,
-		depth: Number,
+		depth: Option<Number>,
 		up: Option<Number>,
+		top: Option<Number>,
+		bottom: Option<Number>,
+		end_depth: Option<Number>,
 	) -> Result<ScriptValue> {
+		let (top, depth) = self.resolve_depth(depth, top, bottom)?;
+		// Tapers the cut from depth at the start to end_depth at the end
+		let end_depth = match end_depth {
+			Some(end_depth) if !end_depth.unit.is_length() => bail!("end_depth must have a length unit"),
+			Some(end_depth) => end_depth.convert_unit(Unit::MM).into(),
+			None => depth,
+		};
+
 		let (x2, y2) = if let Some(up) = up {
-			if up.unit == Unit::None {
-				bail!("up must have a unit");
+			if !up.unit.is_length() {
+				bail!("up must have a length unit");
 			}
 
 			(x1, y1 + up)
@@ -89,38 +196,48 @@ impl ScriptEngine {
 			bail!("Either x2/y2 must be specified or another argument like up");
 		};
 
-		if x1.unit == Unit::None || y1.unit == Unit::None || x2.unit == Unit::None || y2.unit == Unit::None || depth.unit == Unit::None {
-			bail!("All arguments must have a unit");
+		if !x1.unit.is_length() || !y1.unit.is_length() || !x2.unit.is_length() || !y2.unit.is_length() {
+			bail!("All arguments must have a length unit");
 		}
 
-		self.gcode.contour_line(
-			x1.convert_unit(Unit::MM).into(),
-			y1.convert_unit(Unit::MM).into(),
-			x2.convert_unit(Unit::MM).into(),
-			y2.convert_unit(Unit::MM).into(),
-			depth.convert_unit(Unit::MM).into(),
-		)?;
+		let path = [
+			(x1.convert_unit(Unit::MM).into(), y1.convert_unit(Unit::MM).into()),
+			(x2.convert_unit(Unit::MM).into(), y2.convert_unit(Unit::MM).into()),
+		];
+
+		let inputs = [path[0].0, path[0].1, path[1].0, path[1].1, top, depth, end_depth];
+		self.gcode
+			.operation("contour", &inputs, |gcode| gcode.contour_path(&path, top, depth, end_depth))?;
 
 		Ok(ScriptValue::Null)
 	}
 
 	#[ffi_func]
-	fn builtin_drill(&mut self, x: Number, y: Number, depth: Number) -> Result<ScriptValue> {
-		if x.unit == Unit::None || y.unit == Unit::None || depth.unit == Unit::None {
-			bail!("All arguments must have a unit");
+	fn builtin_drill(&mut self, x: Number, y: Number, depth: Option<Number>, top: Option<Number>, bottom: Option<Number>) -> Result<ScriptValue> {
+		let (top, depth) = self.resolve_depth(depth, top, bottom)?;
+
+		if !x.unit.is_length() || !y.unit.is_length() {
+			bail!("All arguments must have a length unit");
 		}
 
-		self.gcode.drill(
-			x.convert_unit(Unit::MM).into(),
-			y.convert_unit(Unit::MM).into(),
-			depth.convert_unit(Unit::MM).into(),
-		);
+		let (x, y) = (x.convert_unit(Unit::MM).into(), y.convert_unit(Unit::MM).into());
+		self.gcode.operation("drill", &[x, y, top, depth], |gcode| gcode.drill(x, y, top, depth))?;
 
 		Ok(ScriptValue::Null)
 	}
 
 	#[ffi_func]
-	fn builtin_circle_pocket(&mut self, cx: Number, cy: Number, diameter: Option<Number>, radius: Option<Number>, depth: Number) -> Result<ScriptValue> {
+	fn builtin_circle_pocket(
+		&mut self,
+		cx: Number,
+		cy: Number,
+		diameter: Option<Number>,
+		radius: Option<Number>,
+		depth: Option<Number>,
+		top: Option<Number>,
+		bottom: Option<Number>,
+	) -> Result<ScriptValue> {
+		let (top, depth) = self.resolve_depth(depth, top, bottom)?;
 		let diameter = if let Some(diameter) = diameter {
 			diameter
 		} else if let Some(radius) = radius {
@@ -129,16 +246,18 @@ impl ScriptEngine {
 			bail!("Either diameter or radius must be specified");
 		};
 
-		if cx.unit == Unit::None || cy.unit == Unit::None || diameter.unit == Unit::None || depth.unit == Unit::None {
-			bail!("All arguments must have a unit");
+		if !cx.unit.is_length() || !cy.unit.is_length() || !diameter.unit.is_length() {
+			bail!("All arguments must have a length unit");
 		}
 
-		self.gcode.circle_pocket(
+		let (cx, cy, diameter) = (
 			cx.convert_unit(Unit::MM).into(),
 			cy.convert_unit(Unit::MM).into(),
 			diameter.convert_unit(Unit::MM).into(),
-			depth.convert_unit(Unit::MM).into(),
-		)?;
+		);
+		self.gcode.operation("circle_pocket", &[cx, cy, diameter, top, depth], |gcode| {
+			gcode.circle_pocket(cx, cy, diameter, top, depth)
+		})?;
 
 		Ok(ScriptValue::Null)
 	}
@@ -154,11 +273,11 @@ impl ScriptEngine {
 		rpm: Number,
 	) -> Result<ScriptValue> {
 		let material = Material {
-			stepover: stepover.as_float().ok_or(anyhow!("stepover must be a number"))?,
-			depth_per_pass: depth_per_pass.as_float().ok_or(anyhow!("depth_per_pass must be a number"))?,
-			feed_rate: feed_rate.as_float().ok_or(anyhow!("feed_rate must be a number"))?,
-			plunge_rate: plunge_rate.as_float().ok_or(anyhow!("plunge_rate must be a number"))?,
-			rpm: rpm.as_float().ok_or(anyhow!("rpm must be a number"))?,
+			stepover: stepover.as_float().ok_or_else(|| anyhow!("stepover must be a number"))?,
+			depth_per_pass: depth_per_pass.as_float().ok_or_else(|| anyhow!("depth_per_pass must be a number"))?,
+			feed_rate: feed_rate.as_float().ok_or_else(|| anyhow!("feed_rate must be a number"))?,
+			plunge_rate: plunge_rate.as_float().ok_or_else(|| anyhow!("plunge_rate must be a number"))?,
+			rpm: rpm.as_float().ok_or_else(|| anyhow!("rpm must be a number"))?,
 		};
 
 		self.materials.insert(name, material);
@@ -167,18 +286,332 @@ impl ScriptEngine {
 	}
 
 	#[ffi_func]
-	fn builtin_groove_pocket(&mut self, x: Number, y: Number, width: Number, height: Number, depth: Number) -> Result<ScriptValue> {
-		if x.unit == Unit::None || y.unit == Unit::None || width.unit == Unit::None || height.unit == Unit::None || depth.unit == Unit::None {
-			bail!("All arguments must have a unit");
+	fn builtin_machine(
+		&mut self,
+		max_feed: Option<Number>,
+		max_z_feed: Option<Number>,
+		rapid_feed: Option<Number>,
+		max_x: Option<Number>,
+		max_y: Option<Number>,
+		max_mrr: Option<Number>,
+		axes: Option<String>,
+	) -> Result<ScriptValue> {
+		if max_x.is_some_and(|max_x| !max_x.unit.is_length()) || max_y.is_some_and(|max_y| !max_y.unit.is_length()) {
+			bail!("max_x and max_y must have a length unit");
 		}
 
-		self.gcode.groove_pocket(
-			x.convert_unit(Unit::MM).into(),
-			y.convert_unit(Unit::MM).into(),
-			width.convert_unit(Unit::MM).into(),
-			height.convert_unit(Unit::MM).into(),
-			depth.convert_unit(Unit::MM).into(),
-		)?;
+		if let Some(max_x) = max_x {
+			self.gcode.machine.max_x = Some(max_x.convert_unit(Unit::MM).into());
+		}
+
+		if let Some(max_y) = max_y {
+			self.gcode.machine.max_y = Some(max_y.convert_unit(Unit::MM).into());
+		}
+
+		if let Some(max_feed) = max_feed {
+			self.gcode.machine.max_feed = Some(max_feed.as_float().ok_or_else(|| anyhow!("max_feed must be a number"))?);
+		}
+
+		if let Some(max_z_feed) = max_z_feed {
+			self.gcode.machine.max_z_feed = Some(max_z_feed.as_float().ok_or_else(|| anyhow!("max_z_feed must be a number"))?);
+		}
+
+		if let Some(rapid_feed) = rapid_feed {
+			self.gcode.machine.rapid_feed = Some(rapid_feed.as_float().ok_or_else(|| anyhow!("rapid_feed must be a number"))?);
+		}
+
+		// In mm^3/min, as units don't go beyond lengths
+		if let Some(max_mrr) = max_mrr {
+			self.gcode.machine.max_mrr = Some(max_mrr.as_float().ok_or_else(|| anyhow!("max_mrr must be a number"))?);
+		}
+
+		// Which program axis each machine axis follows, e.g. 'X,-Y,Z' for a machine whose Y grows toward the operator
+		if let Some(axes) = axes {
+			self.gcode.machine.axes = AxisMap::parse(&axes)?;
+		}
+
+		Ok(ScriptValue::Null)
+	}
+
+	#[ffi_func]
+	fn builtin_postprocessor(
+		&mut self,
+		rapids: Option<String>,
+		flavor: Option<String>,
+		vacuum: Option<String>,
+		quirks: Option<String>,
+		operations: Option<String>,
+		feed_scale: Option<Number>,
+		rpm_scale: Option<Number>,
+	) -> Result<ScriptValue> {
+		if let Some(rapids) = rapids {
+			self.gcode.postprocessor.rapids_as_feed = match rapids.as_str() {
+				"rapid" => false,
+				"feed" => true,
+				_ => bail!("rapids must be 'rapid' or 'feed'"),
+			};
+		}
+
+		if let Some(flavor) = flavor {
+			self.gcode.postprocessor.flavor = match flavor.as_str() {
+				"standard" => Flavor::Standard,
+				"marlin" => Flavor::Marlin,
+				_ => bail!("Unknown flavor: {}", flavor),
+			};
+		}
+
+		if let Some(vacuum) = vacuum {
+			self.gcode.postprocessor.fan_as_vacuum = match vacuum.as_str() {
+				"none" => false,
+				"fan" => true,
+				_ => bail!("vacuum must be 'none' or 'fan'"),
+			};
+		}
+
+		let scale = |scale: Number, name: &str| match scale.as_float() {
+			Some(scale) if scale > 0.0 => Ok(scale),
+			_ => Err(anyhow!("{} must be a positive unitless number", name)),
+		};
+
+		if let Some(feed_scale) = feed_scale {
+			self.gcode.postprocessor.feed_scale = scale(feed_scale, "feed_scale")?;
+		}
+
+		if let Some(rpm_scale) = rpm_scale {
+			self.gcode.postprocessor.rpm_scale = scale(rpm_scale, "rpm_scale")?;
+		}
+
+		// Comma separated list of what to write around each operation, e.g. 'comments,pause', or 'none'
+		if let Some(operations) = operations {
+			let postprocessor = &mut self.gcode.postprocessor;
+			postprocessor.operation_comments = false;
+			postprocessor.pause_operations = false;
+
+			for option in operations.split(',').map(str::trim).filter(|option| !option.is_empty()) {
+				match option {
+					"none" => {},
+					"comments" => postprocessor.operation_comments = true,
+					"pause" => postprocessor.pause_operations = true,
+					_ => bail!("Unknown operations option: {}", option),
+				}
+			}
+		}
+
+		// Comma separated list of word formatting quirks, e.g. 'padded_codes,decimal_point'
+		if let Some(quirks) = quirks {
+			let postprocessor = &mut self.gcode.postprocessor;
+			postprocessor.padded_codes = false;
+			postprocessor.no_spaces = false;
+			postprocessor.decimal_point = false;
+
+			for quirk in quirks.split(',').map(str::trim).filter(|quirk| !quirk.is_empty()) {
+				match quirk {
+					"padded_codes" => postprocessor.padded_codes = true,
+					"no_spaces" => postprocessor.no_spaces = true,
+					"decimal_point" => postprocessor.decimal_point = true,
+					_ => bail!("Unknown quirk: {}", quirk),
+				}
+			}
+		}
+
+		Ok(ScriptValue::Null)
+	}
+
+	#[ffi_func]
+	fn builtin_groove_pocket(
+		&mut self,
+		x: Number,
+		y: Number,
+		width: Number,
+		height: Number,
+		depth: Option<Number>,
+		top: Option<Number>,
+		bottom: Option<Number>,
+		slope: Option<Number>,
+		drain_x: Option<Number>,
+		drain_y: Option<Number>,
+	) -> Result<ScriptValue> {
+		let (top, depth) = self.resolve_depth(depth, top, bottom)?;
+
+		if !x.unit.is_length() || !y.unit.is_length() || !width.unit.is_length() || !height.unit.is_length() {
+			bail!("All arguments must have a length unit");
+		}
+
+		let x: f64 = x.convert_unit(Unit::MM).into();
+		let y: f64 = y.convert_unit(Unit::MM).into();
+		let width: f64 = width.convert_unit(Unit::MM).into();
+		let height: f64 = height.convert_unit(Unit::MM).into();
+
+		// slope is the floor's angle in degrees, falling toward the drain point (the pocket's center by default)
+		let drain = match slope {
+			Some(slope) => {
+				let slope = degrees(slope)?;
+
+				if !(0.0..90.0).contains(&slope) {
+					bail!("slope must be between 0 and 90 degrees");
+				}
+
+				if drain_x.is_some_and(|drain_x| !drain_x.unit.is_length()) || drain_y.is_some_and(|drain_y| !drain_y.unit.is_length()) {
+					bail!("drain_x and drain_y must have a length unit");
+				}
+
+				Some(Drain {
+					x: drain_x.map_or(x + width / 2.0, |drain_x| drain_x.convert_unit(Unit::MM).into()),
+					y: drain_y.map_or(y + height / 2.0, |drain_y| drain_y.convert_unit(Unit::MM).into()),
+					slope: slope.to_radians().tan(),
+				})
+			},
+			None if drain_x.is_some() || drain_y.is_some() => bail!("drain_x and drain_y require slope"),
+			None => None,
+		};
+
+		let drain_inputs = drain.map_or([f64::NAN; 3], |drain| [drain.x, drain.y, drain.slope]);
+		let inputs = [[x, y, width, height, top, depth].as_slice(), &drain_inputs].concat();
+		self.gcode
+			.operation("groove_pocket", &inputs, |gcode| gcode.groove_pocket(x, y, width, height, top, depth, drain))?;
+
+		Ok(ScriptValue::Null)
+	}
+
+	/// Drills a grid of holes over the region, each as deep as the image is dark at that spot.
+	/// With max_diameter, depths are chosen so that a V-bit of vbit_angle cuts holes up to that diameter.
+	#[ffi_func]
+	fn builtin_halftone_drill(
+		&mut self,
+		image: String,
+		x: Number,
+		y: Number,
+		width: Number,
+		height: Number,
+		spacing: Number,
+		max_depth: Option<Number>,
+		max_diameter: Option<Number>,
+		vbit_angle: Option<Number>,
+		top: Option<Number>,
+	) -> Result<ScriptValue> {
+		if !x.unit.is_length() || !y.unit.is_length() || !width.unit.is_length() || !height.unit.is_length() || !spacing.unit.is_length() {
+			bail!("All arguments must have a length unit");
+		}
+
+		let max_depth = match (max_depth, max_diameter) {
+			(Some(max_depth), None) => max_depth,
+			(None, Some(max_diameter)) => {
+				let angle = vbit_angle.map_or(Ok(90.0), degrees)?;
+
+				if angle <= 0.0 || angle >= 180.0 {
+					bail!("vbit_angle must be between 0 and 180 degrees");
+				}
+
+				max_diameter * Number::from_float(0.5 / (angle / 2.0).to_radians().tan())
+			},
+			(Some(_), Some(_)) => bail!("Only one of max_depth or max_diameter can be specified"),
+			(None, None) => bail!("Either max_depth or max_diameter must be specified"),
+		};
+		let (top, max_depth) = self.resolve_depth(Some(max_depth), top, None)?;
+
+		let x: f64 = x.convert_unit(Unit::MM).into();
+		let y: f64 = y.convert_unit(Unit::MM).into();
+		let width: f64 = width.convert_unit(Unit::MM).into();
+		let height: f64 = height.convert_unit(Unit::MM).into();
+		let spacing: f64 = spacing.convert_unit(Unit::MM).into();
+
+		if width <= 0.0 || height <= 0.0 || spacing <= 0.0 {
+			bail!("width, height and spacing must be positive");
+		}
+
+		let image = GrayImage::load_pgm(&image)?;
+		let columns = (width / spacing).floor() as usize;
+		let rows = (height / spacing).floor() as usize;
+		let mut holes = Vec::new();
+
+		// Rows alternate direction to keep travel short
+		for row in 0..rows {
+			for column in 0..columns {
+				let column = if row % 2 == 0 { column } else { columns - 1 - column };
+				let u = (column as f64 + 0.5) / columns as f64;
+				let v = (row as f64 + 0.5) / rows as f64;
+				let depth = (1.0 - image.sample(u, 1.0 - v)) * max_depth;
+
+				// Skip holes too shallow to mark the surface
+				if depth >= 0.01 {
+					holes.push((x + u * width, y + v * height, depth));
+				}
+			}
+		}
+
+		let inputs = holes.iter().flat_map(|&(x, y, depth)| [x, y, depth]).chain([top]).collect::<Vec<_>>();
+		self.gcode.operation("drill_holes", &inputs, |gcode| gcode.drill_holes(&holes, top))?;
+
+		Ok(ScriptValue::Null)
+	}
+
+	/// Pockets the dark modules of a QR code holding text, size across with its lower left corner at x, y.  Readers need a
+	/// light margin four modules wide around the code, which is left for the script to keep clear.
+	#[ffi_func]
+	fn builtin_qr_code(
+		&mut self,
+		x: Number,
+		y: Number,
+		size: Number,
+		text: String,
+		depth: Option<Number>,
+		top: Option<Number>,
+		bottom: Option<Number>,
+	) -> Result<ScriptValue> {
+		let (top, depth) = self.resolve_depth(depth, top, bottom)?;
+
+		if !x.unit.is_length() || !y.unit.is_length() || !size.unit.is_length() {
+			bail!("x, y and size must have a length unit");
+		}
+
+		let x: f64 = x.convert_unit(Unit::MM).into();
+		let y: f64 = y.convert_unit(Unit::MM).into();
+		let size: f64 = size.convert_unit(Unit::MM).into();
+
+		let qr = QrCode::encode(text.as_bytes())?;
+		let module = size / qr.size as f64;
+
+		if module < self.gcode.cutter_diameter {
+			bail!(
+				"The QR code's modules are {:.2}mm across, narrower than the {:.2}mm cutter; make it at least {:.1}mm across",
+				module,
+				self.gcode.cutter_diameter,
+				self.gcode.cutter_diameter * qr.size as f64
+			);
+		}
+
+		// Each run of dark modules down a column is one groove, so that grooves are a single module wide
+		let mut grooves = Vec::new();
+		for column in 0..qr.size {
+			let mut row = 0;
+
+			while row < qr.size {
+				let start = row;
+				while row < qr.size && qr.is_dark(column, row) {
+					row += 1;
+				}
+
+				if row > start {
+					// Rows count down from the top of the code
+					grooves.push((x + column as f64 * module, y + (qr.size - row) as f64 * module, (row - start) as f64 * module));
+				} else {
+					row += 1;
+				}
+			}
+		}
+
+		let inputs = grooves
+			.iter()
+			.flat_map(|&(x, y, height)| [x, y, height])
+			.chain([module, top, depth])
+			.collect::<Vec<_>>();
+		self.gcode.operation("qr_code", &inputs, |gcode| {
+			for &(x, y, height) in &grooves {
+				gcode.groove_pocket(x, y, module, height, top, depth, None)?;
+			}
+
+			Ok(())
+		})?;
 
 		Ok(ScriptValue::Null)
 	}
@@ -190,6 +623,553 @@ impl ScriptEngine {
 		Ok(ScriptValue::Null)
 	}
 
+	/// Stops generation if condition is false, for checking design constraints.
+	#[ffi_func]
+	fn builtin_assert(&mut self, condition: ScriptValue, message: Option<String>) -> Result<ScriptValue> {
+		if !condition.is_truthy() {
+			match message {
+				Some(message) => bail!("Assertion failed: {}", message),
+				None => bail!("Assertion failed"),
+			}
+		}
+
+		Ok(ScriptValue::Null)
+	}
+
+	/// Stops generation with the script's own error message.
+	#[ffi_func]
+	fn builtin_error(&mut self, message: String) -> Result<ScriptValue> {
+		bail!("{}", message)
+	}
+
+	/// Adds a warning, shown with the location of the call like an error, and carries on.
+	#[ffi_func]
+	fn builtin_warn(&mut self, message: String) -> Result<ScriptValue> {
+		let warning = match &self.call_span {
+			Some(span) => span.error(message).to_string(),
+			None => message,
+		};
+
+		self.gcode.warn(warning);
+
+		Ok(ScriptValue::Null)
+	}
+
+	#[ffi_func]
+	fn builtin_stock(&mut self, width: Number, height: Number, thickness: Option<Number>, top: Option<Number>) -> Result<ScriptValue> {
+		if !width.unit.is_length() || !height.unit.is_length() || thickness.is_some_and(|thickness| !thickness.unit.is_length()) {
+			bail!("All arguments must have a length unit");
+		}
+
+		if let Some(top) = top {
+			if !top.unit.is_length() {
+				bail!("top must have a length unit");
+			}
+
+			self.gcode.stock_top = top.convert_unit(Unit::MM).into();
+		}
+
+		self.gcode.stock = Some(Stock {
+			width: width.convert_unit(Unit::MM).into(),
+			height: height.convert_unit(Unit::MM).into(),
+			thickness: thickness.map(|thickness| thickness.convert_unit(Unit::MM).into()),
+		});
+
+		Ok(ScriptValue::Null)
+	}
+
+	#[ffi_func]
+	fn builtin_clearance(&mut self, distance: Number) -> Result<ScriptValue> {
+		if !distance.unit.is_length() {
+			bail!("distance must have a length unit");
+		}
+
+		let distance: f64 = distance.convert_unit(Unit::MM).into();
+
+		if distance < 0.0 {
+			bail!("distance must not be negative");
+		}
+
+		self.gcode.clearance = distance;
+
+		Ok(ScriptValue::Null)
+	}
+
+	#[ffi_func]
+	fn builtin_pause(&mut self, message: String, flip: Option<String>) -> Result<ScriptValue> {
+		let flip_axis = match flip.as_deref() {
+			None => None,
+			Some("x") => Some('x'),
+			Some("y") => Some('y'),
+			Some(axis) => bail!("flip must be 'x' or 'y', not '{}'", axis),
+		};
+
+		self.gcode.pause(&message, flip_axis)?;
+
+		Ok(ScriptValue::Null)
+	}
+
+	#[ffi_func]
+	fn builtin_flip(&mut self, axis: String, pin_depth: Number, pin_inset: Option<Number>) -> Result<ScriptValue> {
+		let pin_inset = pin_inset.unwrap_or(Number::from_int_and_unit(10, "mm"));
+
+		if !pin_depth.unit.is_length() || !pin_inset.unit.is_length() {
+			bail!("pin_depth and pin_inset must have a length unit");
+		}
+
+		let axis = match axis.as_str() {
+			"x" => 'x',
+			"y" => 'y',
+			_ => bail!("axis must be 'x' or 'y', not '{}'", axis),
+		};
+
+		self.gcode
+			.flip(axis, pin_depth.convert_unit(Unit::MM).into(), pin_inset.convert_unit(Unit::MM).into())?;
+
+		Ok(ScriptValue::Null)
+	}
+
+	/// Coordinate offset from one of the stock's edges ('left', 'right', 'bottom' or 'top').
+	/// If size is given, returns the left/bottom coordinate of a feature that size, so that its far side is offset from the edge.
+	#[ffi_func]
+	fn builtin_from_edge(&mut self, edge: String, offset: Number, size: Option<Number>) -> Result<ScriptValue> {
+		let stock = self.gcode.stock.ok_or_else(|| anyhow!("from_edge requires the stock to be defined"))?;
+		let size = size.unwrap_or(Number::from_int_and_unit(0, "mm"));
+
+		if !offset.unit.is_length() || !size.unit.is_length() {
+			bail!("All arguments must have a length unit");
+		}
+
+		let offset: f64 = offset.convert_unit(Unit::MM).into();
+		let size: f64 = size.convert_unit(Unit::MM).into();
+
+		let coordinate = match edge.as_str() {
+			"left" | "bottom" => offset,
+			"right" => stock.width - offset - size,
+			"top" => stock.height - offset - size,
+			_ => bail!("edge must be 'left', 'right', 'bottom' or 'top', not '{}'", edge),
+		};
+
+		Ok(ScriptValue::Number(Number::from_float_and_unit(coordinate, "mm")))
+	}
+
+	/// Left/bottom coordinate along axis ('x' or 'y') of a feature of the given size centered on the stock, or on at if given.
+	#[ffi_func]
+	fn builtin_centered(&mut self, axis: String, size: Number, at: Option<Number>) -> Result<ScriptValue> {
+		if !size.unit.is_length() || at.is_some_and(|at| !at.unit.is_length()) {
+			bail!("All arguments must have a length unit");
+		}
+
+		if axis != "x" && axis != "y" {
+			bail!("axis must be 'x' or 'y', not '{}'", axis);
+		}
+
+		let center = match (at, self.gcode.stock) {
+			(Some(at), _) => at.convert_unit(Unit::MM).into(),
+			(None, Some(stock)) if axis == "x" => stock.width / 2.0,
+			(None, Some(stock)) => stock.height / 2.0,
+			(None, None) => bail!("centered requires the stock to be defined, or at"),
+		};
+		let size: f64 = size.convert_unit(Unit::MM).into();
+
+		Ok(ScriptValue::Number(Number::from_float_and_unit(center - size / 2.0, "mm")))
+	}
+
+	/// The [x, y] lower left corner of a w by h rectangle centered on cx, cy, e.g. for groove_pocket.
+	#[ffi_func]
+	fn builtin_center_rect(&mut self, cx: Number, cy: Number, w: Number, h: Number) -> Result<ScriptValue> {
+		Ok(ScriptValue::List(vec![
+			self.builtin_centered("x".to_string(), w, Some(cx))?,
+			self.builtin_centered("y".to_string(), h, Some(cy))?,
+		]))
+	}
+
+	/// Whether the flag was set when running the script (e.g. from the command line).
+	#[ffi_func]
+	fn builtin_flag(&mut self, name: String) -> Result<ScriptValue> {
+		Ok(self.flags.contains(&name).into())
+	}
+
+	/// Uniformly distributed random number between min and max, in min's unit, as random() or rand().  With a seed, the number comes
+	/// from that seed alone instead of the script's sequence, so e.g. random(0mm, 1mm, seed=i) gives each i the same offset no matter
+	/// what ran before it.
+	#[ffi_func]
+	fn builtin_rand(&mut self, min: Number, max: Number, seed: Option<Number>) -> Result<ScriptValue> {
+		if !min.unit.same_kind(max.unit) {
+			bail!("min and max must both be lengths, both be angles, or both be unitless");
+		}
+
+		let max = max.convert_unit(min.unit);
+		let t = match seed {
+			Some(seed) => {
+				let seed: i64 = seed.try_into().map_err(|_| anyhow!("seed must be an integer"))?;
+				Rng::new(seed as u64).next_f64()
+			},
+			None => self.rng().next_f64(),
+		};
+
+		Ok(ScriptValue::Number(min + (max - min) * t.into()))
+	}
+
+	#[ffi_func]
+	fn builtin_seed(&mut self, seed: Number) -> Result<ScriptValue> {
+		let seed: i64 = seed.try_into().map_err(|_| anyhow!("seed must be an integer"))?;
+
+		self.set_seed(seed as u64);
+
+		Ok(ScriptValue::Null)
+	}
+
+	/// Number of items in a list or range, or characters in a string.
+	#[ffi_func]
+	fn builtin_len(&mut self, value: ScriptValue) -> Result<ScriptValue> {
+		let len = item_count(&value).ok_or_else(|| anyhow!("len: expected a list, range or string"))?;
+
+		Ok(ScriptValue::Number(Number::from_int(len as i64)))
+	}
+
+	/// [index, item] pairs, counting from 0, for loops that need to know which item they're on.
+	#[ffi_func]
+	fn builtin_enumerate(&mut self, items: ScriptValue) -> Result<ScriptValue> {
+		let pairs = items
+			.into_items()?
+			.enumerate()
+			.map(|(i, item)| ScriptValue::List(vec![ScriptValue::Number(Number::from_int(i as i64)), item]));
+
+		Ok(ScriptValue::List(pairs.collect()))
+	}
+
+	/// A new list with item added to the end.  The original list is left unchanged.
+	#[ffi_func]
+	fn builtin_append(&mut self, items: ScriptValue, item: ScriptValue) -> Result<ScriptValue> {
+		let mut items = items.into_items()?.collect::<Vec<_>>();
+		items.push(item);
+
+		Ok(ScriptValue::List(items))
+	}
+
+	/// The indices of a list, range or string, so that `for i in range(points)` can look at neighbouring items.
+	#[ffi_func]
+	fn builtin_range(&mut self, items: ScriptValue) -> Result<ScriptValue> {
+		let num = item_count(&items).ok_or_else(|| anyhow!("range: expected a list, range or string"))?;
+
+		Ok(ScriptValue::Range {
+			start: Number::from_int(0),
+			step: Number::from_int(1),
+			num,
+		})
+	}
+
+	/// Name of the value's type: number, string, boolean, list, range or null.
+	#[ffi_func]
+	fn builtin_typeof(&mut self, value: ScriptValue) -> Result<ScriptValue> {
+		Ok(ScriptValue::String(value.type_name().to_string()))
+	}
+
+	#[ffi_func]
+	fn builtin_is_number(&mut self, value: ScriptValue) -> Result<ScriptValue> {
+		Ok(ScriptValue::Bool(matches!(value, ScriptValue::Number(_))))
+	}
+
+	#[ffi_func]
+	fn builtin_is_string(&mut self, value: ScriptValue) -> Result<ScriptValue> {
+		Ok(ScriptValue::Bool(matches!(value, ScriptValue::String(_))))
+	}
+
+	#[ffi_func]
+	fn builtin_is_bool(&mut self, value: ScriptValue) -> Result<ScriptValue> {
+		Ok(ScriptValue::Bool(matches!(value, ScriptValue::Bool(_))))
+	}
+
+	/// True for lists and ranges, which can both be looped over.
+	#[ffi_func]
+	fn builtin_is_list(&mut self, value: ScriptValue) -> Result<ScriptValue> {
+		Ok(ScriptValue::Bool(matches!(value, ScriptValue::List(_) | ScriptValue::Range { .. })))
+	}
+
+	#[ffi_func]
+	fn builtin_is_null(&mut self, value: ScriptValue) -> Result<ScriptValue> {
+		Ok(ScriptValue::Bool(matches!(value, ScriptValue::Null)))
+	}
+
+	/// Whether a variable, or a set engine value like stock.width, exists with the name.  Lets an include file give defaults
+	/// only for what the including script hasn't set.
+	#[ffi_func]
+	fn builtin_is_defined(&mut self, name: String) -> Result<ScriptValue> {
+		let defined = self.variable(&name).is_some() || self.builtin_variable(&name).is_ok_and(|value| value.is_some());
+
+		Ok(ScriptValue::Bool(defined))
+	}
+
+	// Unitless angles are in degrees, like OpenSCAD, since that's how drawings give them; 1.5rad works too
+	#[ffi_func]
+	fn builtin_sin(&mut self, angle: Number) -> Result<ScriptValue> {
+		Ok(ScriptValue::Number(Number::from_float(degrees(angle)?.to_radians().sin())))
+	}
+
+	#[ffi_func]
+	fn builtin_cos(&mut self, angle: Number) -> Result<ScriptValue> {
+		Ok(ScriptValue::Number(Number::from_float(degrees(angle)?.to_radians().cos())))
+	}
+
+	#[ffi_func]
+	fn builtin_tan(&mut self, angle: Number) -> Result<ScriptValue> {
+		Ok(ScriptValue::Number(Number::from_float(degrees(angle)?.to_radians().tan())))
+	}
+
+	/// Angle in deg of the point (x, y) from the positive X axis, from -180deg to 180deg.
+	#[ffi_func]
+	fn builtin_atan2(&mut self, y: Number, x: Number) -> Result<ScriptValue> {
+		if !y.unit.same_kind(x.unit) {
+			bail!("y and x must both have a unit, or both be unitless");
+		}
+
+		let x = x.convert_unit(y.unit);
+
+		Ok(ScriptValue::Number(Number {
+			value: InnerValue::Float(f64::from(y).atan2(x.into()).to_degrees()),
+			unit: Unit::Deg,
+		}))
+	}
+
+	/// The [x, y] point at radius and angle (in degrees, counterclockwise from +X) around cx, cy, in the unit of radius.
+	#[ffi_func]
+	fn builtin_polar(&mut self, radius: Number, angle: Number, cx: Option<Number>, cy: Option<Number>) -> Result<ScriptValue> {
+		if radius.unit.is_angle() {
+			bail!("radius must be a length");
+		}
+
+		let (sin, cos) = degrees(angle)?.to_radians().sin_cos();
+		let center = |c: Option<Number>| match c {
+			Some(c) if !c.unit.same_kind(radius.unit) => bail!("cx and cy must have a unit if radius does"),
+			Some(c) => Ok(c.convert_unit(radius.unit)),
+			None => Ok(Number {
+				value: InnerValue::Integer(0),
+				unit: radius.unit,
+			}),
+		};
+
+		Ok(ScriptValue::List(vec![
+			ScriptValue::Number(center(cx)? + radius * cos.into()),
+			ScriptValue::Number(center(cy)? + radius * sin.into()),
+		]))
+	}
+
+	/// Square root, keeping the unit so that sqrt(a*a + b*b) of lengths is a length.
+	#[ffi_func]
+	fn builtin_sqrt(&mut self, x: Number) -> Result<ScriptValue> {
+		if f64::from(x) < 0.0 {
+			bail!("Cannot take the square root of a negative number");
+		}
+
+		Ok(ScriptValue::Number(Number {
+			value: InnerValue::Float(f64::from(x).sqrt()),
+			unit: x.unit,
+		}))
+	}
+
+	/// Length of the hypotenuse, sqrt(x^2 + y^2), in the unit of x.
+	#[ffi_func]
+	fn builtin_hypot(&mut self, x: Number, y: Number) -> Result<ScriptValue> {
+		if !x.unit.same_kind(y.unit) {
+			bail!("x and y must both have the same kind of unit, or both be unitless");
+		}
+
+		let y = y.convert_unit(x.unit);
+
+		Ok(ScriptValue::Number(Number {
+			value: InnerValue::Float(f64::from(x).hypot(y.into())),
+			unit: x.unit,
+		}))
+	}
+
+	// Rounding keeps the unit, so floor(2.7in) is 2in, and gives an integer so the result can be used as a count or index
+	#[ffi_func]
+	fn builtin_floor(&mut self, x: Number) -> Result<ScriptValue> {
+		to_integer(x, f64::floor)
+	}
+
+	#[ffi_func]
+	fn builtin_ceil(&mut self, x: Number) -> Result<ScriptValue> {
+		to_integer(x, f64::ceil)
+	}
+
+	/// Rounds to the nearest integer, with halves rounded away from zero.
+	#[ffi_func]
+	fn builtin_round(&mut self, x: Number) -> Result<ScriptValue> {
+		to_integer(x, f64::round)
+	}
+
+	#[ffi_func]
+	fn builtin_abs(&mut self, x: Number) -> Result<ScriptValue> {
+		let value = match x.value {
+			InnerValue::Integer(i) => InnerValue::Integer(i.checked_abs().ok_or_else(|| anyhow!("abs overflowed"))?),
+			InnerValue::Float(f) => InnerValue::Float(f.abs()),
+		};
+
+		Ok(ScriptValue::Number(Number { value, unit: x.unit }))
+	}
+
+	/// Limits value to between low and high, in the unit of value.
+	#[ffi_func]
+	fn builtin_clamp(&mut self, value: Number, low: Number, high: Number) -> Result<ScriptValue> {
+		if !value.can_mix(&low) || !value.can_mix(&high) {
+			bail!("clamp: lengths and angles don't mix");
+		}
+
+		let (low, high) = (low.convert_unit(value.unit), high.convert_unit(value.unit));
+
+		if low.compare(&high) == Some(Ordering::Greater) {
+			bail!("clamp: low must not be greater than high");
+		}
+
+		Ok(ScriptValue::Number(if value.compare(&low) == Some(Ordering::Less) {
+			low
+		} else if value.compare(&high) == Some(Ordering::Greater) {
+			high
+		} else {
+			value
+		}))
+	}
+
+	/// Calls function with each item, returning a list of the results.
+	#[ffi_func]
+	fn builtin_map(&mut self, items: ScriptValue, function: ScriptValue) -> Result<ScriptValue> {
+		let (function, span) = self.callback(function)?;
+
+		items
+			.into_items()?
+			.map(|item| self.call_function(&function, vec![item], HashMap::new(), &span))
+			.collect::<Result<_>>()
+			.map(ScriptValue::List)
+	}
+
+	/// Items for which function returns a true value.
+	#[ffi_func]
+	fn builtin_filter(&mut self, items: ScriptValue, function: ScriptValue) -> Result<ScriptValue> {
+		let (function, span) = self.callback(function)?;
+		let mut kept = Vec::new();
+
+		for item in items.into_items()? {
+			if self.call_function(&function, vec![item.clone()], HashMap::new(), &span)?.is_truthy() {
+				kept.push(item);
+			}
+		}
+
+		Ok(ScriptValue::List(kept))
+	}
+
+	/// Items in ascending order, compared with units converted.  by picks what to compare: 'x', 'y' or 'z' for that coordinate
+	/// of points, or a function returning the value to compare.  Items that compare equal keep their order.
+	#[ffi_func]
+	fn builtin_sort(&mut self, items: ScriptValue, by: Option<ScriptValue>) -> Result<ScriptValue> {
+		let mut keyed = self.sort_keys(items, by)?;
+		let mut error = None;
+
+		keyed.sort_by(|(a, _), (b, _)| {
+			a.compare(b).unwrap_or_else(|e| {
+				error.get_or_insert(e);
+				Ordering::Equal
+			})
+		});
+
+		match error {
+			Some(e) => Err(e),
+			None => Ok(ScriptValue::List(keyed.into_iter().map(|(_, item)| item).collect())),
+		}
+	}
+
+	/// The item that sorts first, with by as in sort().
+	#[ffi_func]
+	fn builtin_min_of(&mut self, items: ScriptValue, by: Option<ScriptValue>) -> Result<ScriptValue> {
+		self.extreme_of(items, by, Ordering::Less)
+	}
+
+	/// The item that sorts last, with by as in sort().
+	#[ffi_func]
+	fn builtin_max_of(&mut self, items: ScriptValue, by: Option<ScriptValue>) -> Result<ScriptValue> {
+		self.extreme_of(items, by, Ordering::Greater)
+	}
+
+	fn extreme_of(&mut self, items: ScriptValue, by: Option<ScriptValue>, wanted: Ordering) -> Result<ScriptValue> {
+		let mut best: Option<(ScriptValue, ScriptValue)> = None;
+
+		for (key, item) in self.sort_keys(items, by)? {
+			best = match best {
+				Some((best_key, _)) if key.compare(&best_key)? == wanted => Some((key, item)),
+				Some(best) => Some(best),
+				None => Some((key, item)),
+			};
+		}
+
+		best.map(|(_, item)| item).ok_or_else(|| anyhow!("Expected at least one item"))
+	}
+
+	/// Pairs each item with the value it is sorted by.
+	fn sort_keys(&mut self, items: ScriptValue, by: Option<ScriptValue>) -> Result<Vec<(ScriptValue, ScriptValue)>> {
+		let items = items.into_items()?;
+
+		match by {
+			None => Ok(items.map(|item| (item.clone(), item)).collect()),
+			Some(ScriptValue::String(axis)) => {
+				let index = match axis.as_str() {
+					"x" => 0,
+					"y" => 1,
+					"z" => 2,
+					_ => bail!("by must be 'x', 'y', 'z' or a function, not '{}'", axis),
+				};
+				let index = ScriptValue::Number(Number::from_int(index));
+
+				items.map(|item| Ok((item.index(&index)?, item))).collect()
+			},
+			Some(function @ ScriptValue::Function(_)) => {
+				let (function, span) = self.callback(function)?;
+
+				items
+					.map(|item| Ok((self.call_function(&function, vec![item.clone()], HashMap::new(), &span)?, item)))
+					.collect()
+			},
+			Some(by) => bail!("by must be 'x', 'y', 'z' or a function, not a {}", by.type_name()),
+		}
+	}
+
+	/// Items in the opposite order, e.g. a path traced the other way.  Strings are reversed by character.
+	#[ffi_func]
+	fn builtin_reverse(&mut self, items: ScriptValue) -> Result<ScriptValue> {
+		if let ScriptValue::String(s) = items {
+			return Ok(ScriptValue::String(s.chars().rev().collect()));
+		}
+
+		let mut items = items.into_items()?.collect::<Vec<_>>();
+		items.reverse();
+
+		Ok(ScriptValue::List(items))
+	}
+
+	/// Whether a closed path of [x, y] points winds clockwise, with Y pointing up.  The path closes back to its first point.
+	#[ffi_func]
+	fn builtin_is_clockwise(&mut self, path: ScriptValue) -> Result<ScriptValue> {
+		let points = path_points(path)?;
+
+		// Shoelace formula; the area is negative for clockwise paths
+		let area: f64 = points.iter().zip(points.iter().cycle().skip(1)).map(|(a, b)| a.0 * b.1 - b.0 * a.1).sum();
+
+		if area == 0.0 {
+			bail!("The path encloses no area, so it has no winding");
+		}
+
+		Ok((area < 0.0).into())
+	}
+
+	/// Checks a function argument, returning its name and the span of the builtin call to report errors at.
+	fn callback(&self, function: ScriptValue) -> Result<(String, Span)> {
+		let ScriptValue::Function(function) = function else {
+			bail!("Expected a function, got a {}", function.type_name());
+		};
+
+		Ok((function, self.call_span.clone().expect("Builtin called outside of a call")))
+	}
+
 	#[ffi_func]
 	fn builtin_linspace(&mut self, start: Number, stop: Number, num: Number) -> Result<ScriptValue> {
 		if num.unit != Unit::None {
@@ -204,6 +1184,10 @@ impl ScriptEngine {
 			bail!("stop must have a unit if start has a unit");
 		}
 
+		if !start.unit.same_kind(stop.unit) {
+			bail!("start and stop must both be lengths or both be angles");
+		}
+
 		let stop = stop.convert_unit(start.unit);
 		let num: i64 = num.try_into().map_err(|_| anyhow!("num argument must be an integer"))?;
 		let mut step = (stop - start) / (num - 1).into();
@@ -233,8 +1217,8 @@ impl ScriptEngine {
 
 	#[ffi_func]
 	fn builtin_translate(&mut self, x: Number, y: Number) -> Result<ScriptValue> {
-		if x.unit == Unit::None || y.unit == Unit::None {
-			bail!("All arguments must have a unit");
+		if !x.unit.is_length() || !y.unit.is_length() {
+			bail!("All arguments must have a length unit");
 		}
 
 		let x: f64 = x.convert_unit(Unit::MM).into();
@@ -244,4 +1228,157 @@ impl ScriptEngine {
 
 		Ok(ScriptValue::Null)
 	}
+
+	/// Rotates everything after it counterclockwise around the origin.
+	#[ffi_func]
+	fn builtin_rotate(&mut self, angle: Number) -> Result<ScriptValue> {
+		self.gcode.transformation *= Matrix3::new_rotation(degrees(angle)?.to_radians());
+
+		Ok(ScriptValue::Null)
+	}
+
+	/// Works out the Z range of a cut from an operation's depth/top/bottom arguments, returning (top, depth) in mm.
+	/// top defaults to the stock top; either depth (measured down from top) or an absolute bottom Z must be given.
+	fn resolve_depth(&self, depth: Option<Number>, top: Option<Number>, bottom: Option<Number>) -> Result<(f64, f64)> {
+		let top = match top {
+			Some(top) if !top.unit.is_length() => bail!("top must have a length unit"),
+			Some(top) => top.convert_unit(Unit::MM).into(),
+			None => self.gcode.stock_top,
+		};
+
+		let depth = match (depth, bottom) {
+			(Some(_), Some(_)) => bail!("Only one of depth or bottom can be specified"),
+			(Some(depth), None) if !depth.unit.is_length() => bail!("depth must have a length unit"),
+			(Some(depth), None) => depth.convert_unit(Unit::MM).into(),
+			(None, Some(bottom)) if !bottom.unit.is_length() => bail!("bottom must have a length unit"),
+			(None, Some(bottom)) => top - f64::from(bottom.convert_unit(Unit::MM)),
+			(None, None) => bail!("Either depth or bottom must be specified"),
+		};
+
+		Ok((top, depth))
+	}
+}
+
+
+/// An angle argument in degrees.  Unitless angles are taken to be degrees, as they were before angles had units.
+fn degrees(angle: Number) -> Result<f64> {
+	if angle.unit.is_length() {
+		bail!("Angles must be in deg or rad, or unitless degrees, not {}", angle.unit);
+	}
+
+	Ok(angle.convert_unit(Unit::Deg).into())
+}
+
+
+/// Writes its arguments to stderr, separated by spaces, for debugging scripts without touching the G-code.
+fn print(args: Vec<ScriptValue>, nargs: HashMap<String, ScriptValue>) -> Result<ScriptValue> {
+	if let Some(arg) = nargs.into_keys().next() {
+		bail!("print: unknown named argument {}", arg);
+	}
+
+	eprintln!("{}", args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().join(" "));
+
+	Ok(ScriptValue::Null)
+}
+
+
+/// format(template, values...), see format::format.
+fn format(args: Vec<ScriptValue>, nargs: HashMap<String, ScriptValue>) -> Result<ScriptValue> {
+	if let Some(arg) = nargs.into_keys().next() {
+		bail!("format: unknown named argument {}", arg);
+	}
+
+	let Some(ScriptValue::String(template)) = args.first() else {
+		bail!("format: the first argument must be a format string");
+	};
+
+	Ok(ScriptValue::String(crate::format::format(template, &args[1..])?))
+}
+
+
+/// min() and max(), which take any number of numbers or a single list of them.  Units are compared after conversion, and the
+/// winning value is returned in its own unit.
+fn extreme(name: &str, args: Vec<ScriptValue>, nargs: HashMap<String, ScriptValue>, wanted: Ordering) -> Result<ScriptValue> {
+	if let Some(arg) = nargs.into_keys().next() {
+		bail!("{}: unknown named argument {}", name, arg);
+	}
+
+	let items = match <[ScriptValue; 1]>::try_from(args) {
+		Ok([ScriptValue::List(items)]) => items,
+		Ok(args) => args.into(),
+		Err(args) => args,
+	};
+
+	let mut best: Option<Number> = None;
+
+	for item in items {
+		let ScriptValue::Number(item) = item else {
+			bail!("{}: expected numbers, got a {}", name, item.type_name());
+		};
+
+		best = match best {
+			Some(best) if !item.can_mix(&best) => bail!("{}: lengths and angles don't mix", name),
+			Some(best) => match item.compare(&best) {
+				Some(ordering) if ordering == wanted => Some(item),
+				Some(_) => Some(best),
+				None => bail!("{}: cannot compare NaN", name),
+			},
+			None => Some(item),
+		};
+	}
+
+	best.map(ScriptValue::Number).ok_or_else(|| anyhow!("{}: expected at least one number", name))
+}
+
+
+fn item_count(value: &ScriptValue) -> Option<usize> {
+	match value {
+		ScriptValue::List(items) => Some(items.len()),
+		ScriptValue::Range { num, .. } => Some(*num),
+		ScriptValue::String(s) => Some(s.chars().count()),
+		_ => None,
+	}
+}
+
+
+/// Points of a path given as a list of [x, y] lists, in mm.
+fn path_points(path: ScriptValue) -> Result<Vec<(f64, f64)>> {
+	let ScriptValue::List(points) = path else {
+		bail!("Expected a path as a list of [x, y] points, got a {}", path.type_name());
+	};
+
+	points
+		.into_iter()
+		.map(|point| match point {
+			ScriptValue::List(point) => match point.as_slice() {
+				[ScriptValue::Number(x), ScriptValue::Number(y)] if x.unit.is_length() && y.unit.is_length() => {
+					Ok((x.convert_unit(Unit::MM).into(), y.convert_unit(Unit::MM).into()))
+				},
+				_ => bail!("Path points must be [x, y] with units"),
+			},
+			point => bail!("Path points must be [x, y] lists, got a {}", point.type_name()),
+		})
+		.collect()
+}
+
+
+fn to_integer(x: Number, round: fn(f64) -> f64) -> Result<ScriptValue> {
+	let value = match x.value {
+		InnerValue::Integer(i) => i,
+		InnerValue::Float(f) => {
+			let rounded = round(f);
+
+			// i64::MAX as f64 rounds up to 2^63, which is out of range, so the upper bound is exclusive
+			if !(i64::MIN as f64..i64::MAX as f64).contains(&rounded) {
+				bail!("Value is too large to round to an integer");
+			}
+
+			rounded as i64
+		},
+	};
+
+	Ok(ScriptValue::Number(Number {
+		value: InnerValue::Integer(value),
+		unit: x.unit,
+	}))
 }
@@ -1,14 +1,27 @@
+mod ast;
 mod builtins;
+mod parse_error;
 
-use std::{collections::HashMap, io::Write, path::Path};
-
-use pest::{
-	pratt_parser::{Assoc, Op, PrattParser},
-	Parser,
+use std::{
+	cmp::Ordering,
+	collections::{BTreeSet, HashMap},
+	io::Write,
+	path::{Path, PathBuf},
+	rc::Rc,
+	time::{Duration, Instant},
 };
+
+use pest::Parser;
 use pest_derive::Parser;
 
-use crate::{gcode::GcodeState, numbers::Number, value::ScriptValue};
+use self::ast::{BinaryOp, Expr, ExprKind, Function, Source, Span, Stmt, UnaryOp};
+use crate::{
+	cancel::CancellationToken,
+	gcode::{GcodeSnapshot, GcodeState},
+	numbers::{InnerValue, Number, Unit},
+	random::Rng,
+	value::ScriptValue,
+};
 use anyhow::{bail, Context, Result};
 
 
@@ -17,9 +30,92 @@ use anyhow::{bail, Context, Result};
 pub struct ScriptParser;
 
 
+/// How far through a script the engine is, reported before each builtin call.
+pub struct Progress<'a> {
+	/// Top level statements of the current script that have finished.
+	pub completed: usize,
+	pub total: usize,
+	/// Estimate of how much of the current script has run, from 0 to 1.  Unlike completed, this advances as the loops being run
+	/// go through their iterations.
+	pub fraction: f64,
+	pub builtin: &'a str,
+}
+
+/// Time spent in each phase of generating a program, accumulated over every script run.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+	pub parse: Duration,
+	/// Evaluating the script itself, excluding time spent inside builtins.
+	pub eval: Duration,
+	/// Generating toolpaths and other work inside builtins.
+	pub builtins: Duration,
+	pub write: Duration,
+}
+
+/// Set by break, continue or return, and cleared by the loop or function call it applies to.
+enum ControlFlow {
+	Break,
+	Continue,
+	Return(ScriptValue),
+}
+
+type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
+/// Default limit on nested user function calls.  Low enough for the 2MB stack of a spawned thread in release builds, or the 8MB
+/// main thread in debug builds.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 100;
+
+type Scope = HashMap<String, Variable>;
+
+#[derive(Clone)]
+struct Variable {
+	value: ScriptValue,
+	/// The const declaration, if this can't be assigned to.
+	constant: Option<Span>,
+}
+
+/// Engine state saved by ScriptEngine::snapshot, so that an editor can roll back and re-run only the statements after it.
+pub struct Snapshot {
+	global_vars: Scope,
+	functions: HashMap<String, Rc<Function>>,
+	materials: HashMap<String, Material>,
+	rng: Option<Rng>,
+	gcode: GcodeSnapshot,
+}
+
+
 pub struct ScriptEngine {
-	global_vars: HashMap<String, ScriptValue>,
+	global_vars: Scope,
+	/// Variables local to the blocks being run, innermost last.  Globals are not included.
+	scopes: Vec<Scope>,
 	materials: HashMap<String, Material>,
+	flags: BTreeSet<String>,
+	/// Values for params, set from outside the script.
+	params: HashMap<String, ScriptValue>,
+	/// Params the scripts run so far have declared.
+	declared_params: BTreeSet<String>,
+	seed: Option<u64>,
+	rng: Option<Rng>,
+	progress_callback: Option<ProgressCallback>,
+	statements_completed: usize,
+	statements_total: usize,
+	/// The current iteration and number of iterations of each loop being run, outermost first, for estimating progress.
+	loops: Vec<(usize, usize)>,
+	timings: Timings,
+	/// Builtin calls being run, which map, filter and sort can nest through user functions.  Only the outermost is timed.
+	builtin_depth: usize,
+	loop_depth: usize,
+	control_flow: Option<ControlFlow>,
+	functions: HashMap<String, Rc<Function>>,
+	call_depth: usize,
+	max_call_depth: usize,
+	/// Loop iterations and function calls in the current run, counted against max_steps.
+	steps: u64,
+	max_steps: Option<u64>,
+	/// The builtin call being run, so that warn() can point at it.
+	call_span: Option<Span>,
+	/// Files currently being run, outermost first, for resolving and detecting cycles in includes.
+	include_stack: Vec<PathBuf>,
 	gcode: GcodeState,
 }
 
@@ -29,34 +125,142 @@ impl ScriptEngine {
 
 		Self {
 			global_vars: HashMap::new(),
+			scopes: Vec::new(),
 			materials: HashMap::new(),
+			flags: BTreeSet::new(),
+			params: HashMap::new(),
+			declared_params: BTreeSet::new(),
+			seed: None,
+			rng: None,
+			progress_callback: None,
+			statements_completed: 0,
+			statements_total: 0,
+			loops: Vec::new(),
+			timings: Timings::default(),
+			builtin_depth: 0,
+			loop_depth: 0,
+			control_flow: None,
+			functions: HashMap::new(),
+			call_depth: 0,
+			max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+			steps: 0,
+			max_steps: None,
+			call_span: None,
+			include_stack: Vec::new(),
 			gcode,
 		}
 	}
 
+	/// Clears the program, transformations, variables and functions so the engine can generate another program without
+	/// leaking state from the last one.  Materials, the current material and cutter, and settings such as flags, seed and
+	/// callbacks are kept.
+	pub fn reset(&mut self) {
+		self.global_vars.clear();
+		self.scopes.clear();
+		self.functions.clear();
+		self.declared_params.clear();
+		self.include_stack.clear();
+		self.loop_depth = 0;
+		self.control_flow = None;
+		self.call_depth = 0;
+		self.call_span = None;
+		self.steps = 0;
+		self.statements_completed = 0;
+		self.statements_total = 0;
+		self.loops.clear();
+		self.timings = Timings::default();
+		self.builtin_depth = 0;
+		self.rng = None;
+		self.gcode.reset();
+	}
+
+	/// Saves the variables, functions, materials, settings and length of the program generated so far.  Take it between
+	/// runs, for example after running the unchanged start of a script on its own.
+	pub fn snapshot(&self) -> Snapshot {
+		Snapshot {
+			global_vars: self.global_vars.clone(),
+			functions: self.functions.clone(),
+			materials: self.materials.clone(),
+			rng: self.rng.clone(),
+			gcode: self.gcode.snapshot(),
+		}
+	}
+
+	/// Rolls back to a snapshot, dropping everything the scripts run since then did.
+	pub fn restore(&mut self, snapshot: &Snapshot) {
+		self.global_vars = snapshot.global_vars.clone();
+		self.functions = snapshot.functions.clone();
+		self.materials = snapshot.materials.clone();
+		self.rng = snapshot.rng.clone();
+		self.gcode.restore(&snapshot.gcode);
+	}
+
 	pub fn run_file<P: AsRef<Path>>(&mut self, path: P, verbose: bool) -> Result<()> {
-		let unparsed_file = std::fs::read_to_string(path.as_ref()).with_context(|| format!("Failed to read file: {}", path.as_ref().display()))?;
+		let (source, canonical) = read_script(path.as_ref())?;
+		let program = self.parse(source, verbose)?;
+
+		self.include_stack.push(canonical);
+		let result = self.run_program(&program);
+		self.include_stack.pop();
 
-		self.run(&unparsed_file, verbose)
+		result
 	}
 
 	pub fn run(&mut self, source: &str, verbose: bool) -> Result<()> {
-		let pairs = ScriptParser::parse(Rule::program, source)?;
+		let program = self.parse(
+			Source {
+				text: source.to_string(),
+				path: None,
+			},
+			verbose,
+		)?;
+
+		self.run_program(&program)
+	}
+
+	fn parse(&mut self, source: Source, verbose: bool) -> Result<Vec<Stmt>> {
+		let start = Instant::now();
+		let source = Rc::new(source);
+		let pairs = ScriptParser::parse(Rule::program, &source.text).map_err(|e| parse_error::humanize(e, &source.text, source.path.as_deref()))?;
 
 		if verbose {
 			self.format_parse_tree(pairs.clone(), 0);
 		}
 
-		for pair in pairs {
-			match pair.as_rule() {
-				Rule::expr | Rule::forLoop => {
-					self.exec(pair)?;
-				},
-				Rule::EOI => {},
-				_ => bail!("Unexpected rule: {:?}", pair.as_rule()),
+		let program = ast::compile(pairs, &source)?;
+		self.timings.parse += start.elapsed();
+
+		Ok(program)
+	}
+
+	fn run_program(&mut self, program: &[Stmt]) -> Result<()> {
+		self.statements_completed = 0;
+		self.loop_depth = 0;
+		self.control_flow = None;
+		self.scopes.clear();
+		self.call_depth = 0;
+		self.builtin_depth = 0;
+		self.steps = 0;
+		self.statements_total = program.len();
+		self.loops.clear();
+
+		let start = Instant::now();
+		let before = self.timings;
+
+		for stmt in program {
+			self.exec(stmt)?;
+			self.statements_completed += 1;
+
+			// A top level return ends the script
+			if self.control_flow.take().is_some() {
+				break;
 			}
 		}
 
+		// Included files are parsed while running
+		let elsewhere = (self.timings.builtins - before.builtins) + (self.timings.parse - before.parse);
+		self.timings.eval += start.elapsed().saturating_sub(elsewhere);
+
 		Ok(())
 	}
 
@@ -78,191 +282,652 @@ impl ScriptEngine {
 		}
 	}
 
+	/// Calls callback before each builtin call, so that long generations can show progress.
+	pub fn set_progress_callback<F: FnMut(&Progress) + 'static>(&mut self, callback: F) {
+		self.progress_callback = Some(Box::new(callback));
+	}
+
+	/// Makes the script stop with an error once token is cancelled, e.g. from another thread.
+	pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+		self.gcode.cancellation = token;
+	}
+
+	/// Limits how deeply user functions can call each other, so runaway recursion is reported as a script error instead of
+	/// overflowing the stack.  The default is 100.  Each level takes roughly 5KB of native stack in release builds and 50KB
+	/// in debug builds, more for deeply nested code, so deeper limits need the engine to run on a thread with a large stack,
+	/// as the gcad CLI does.
+	pub fn set_max_call_depth(&mut self, depth: usize) {
+		self.max_call_depth = depth;
+	}
+
+	/// Caches the code generated by each toolpath operation, so that re-running an edited script only regenerates the
+	/// operations whose inputs or settings changed.  The cache is kept across reset and restore.
+	pub fn set_operation_cache(&mut self, enabled: bool) {
+		self.gcode.set_operation_cache(enabled);
+	}
+
+	/// Pauses the program before each operation, for stepping through it while proving it.  Scripts can override this with
+	/// postprocessor(operations=...).
+	pub fn set_pause_operations(&mut self, pause: bool) {
+		self.gcode.postprocessor.pause_operations = pause;
+	}
+
+	/// Multiplies every feed rate and spindle speed in the output, for proving a program at reduced speed.  Scripts can
+	/// override these with postprocessor(feed_scale=..., rpm_scale=...).
+	pub fn set_speed_scale(&mut self, feed_scale: f64, rpm_scale: f64) {
+		self.gcode.postprocessor.feed_scale = feed_scale;
+		self.gcode.postprocessor.rpm_scale = rpm_scale;
+	}
+
+	/// Overrides the value of a param declared by the script.  value is an expression, such as 120mm.
+	pub fn set_param(&mut self, name: &str, value: &str) -> Result<()> {
+		let source = Rc::new(Source {
+			text: value.to_string(),
+			path: None,
+		});
+		let pairs = ScriptParser::parse(Rule::standaloneExpr, &source.text).map_err(|e| parse_error::humanize(e, &source.text, None))?;
+		let value = self.eval(&ast::compile_expr(pairs, &source)?)?;
+
+		self.params.insert(name.to_string(), value);
+
+		Ok(())
+	}
+
+	/// Params set with set_param that no script run so far has declared, which are likely misspelled.
+	pub fn undeclared_params(&self) -> Vec<&str> {
+		let mut names = self
+			.params
+			.keys()
+			.filter(|name| !self.declared_params.contains(*name))
+			.map(String::as_str)
+			.collect::<Vec<_>>();
+		names.sort();
+		names
+	}
+
+	/// Limits the total number of loop iterations and user function calls in a script run, so that a runaway script fails
+	/// with an error instead of running for hours.  Unlimited by default.
+	pub fn set_max_steps(&mut self, steps: u64) {
+		self.max_steps = Some(steps);
+	}
+
+	/// Sets a flag that scripts can check with flag(), for building variants of a part from one script.
+	pub fn set_flag(&mut self, flag: &str) {
+		self.flags.insert(flag.to_string());
+	}
+
+	/// Seed for rand().  Without one, a seed is picked from the clock the first time rand() is used.
+	/// Either way the seed is recorded in the output, so a program can always be regenerated exactly.
+	pub fn set_seed(&mut self, seed: u64) {
+		self.seed = Some(seed);
+		self.rng = None;
+	}
+
+	fn rng(&mut self) -> &mut Rng {
+		if self.rng.is_none() {
+			let seed = self.seed.unwrap_or_else(|| {
+				std::time::SystemTime::now()
+					.duration_since(std::time::UNIX_EPOCH)
+					.map(|d| d.as_nanos() as u64)
+					.unwrap_or(0)
+			});
+
+			self.gcode.write_comment(&format!("Random seed: {}", seed));
+			self.rng = Some(Rng::new(seed));
+		}
+
+		self.rng.as_mut().unwrap()
+	}
+
 	pub fn write_header(&mut self) {
-		self.gcode.write_header()
+		self.gcode.write_header();
+
+		if !self.flags.is_empty() {
+			let flags = self.flags.iter().cloned().collect::<Vec<_>>().join(", ");
+			self.gcode.write_comment(&format!("Flags: {}", flags));
+		}
 	}
 
 	pub fn finish<W: Write>(&mut self, writer: W) -> Result<()> {
-		self.gcode.finish(writer)
-	}
+		let start = Instant::now();
+		self.gcode.finish(writer)?;
+		self.timings.write += start.elapsed();
 
-	fn exec(&mut self, pair: pest::iterators::Pair<Rule>) -> Result<ScriptValue> {
-		let pratt = PrattParser::new()
-			.op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::subtract, Assoc::Left))
-			.op(Op::infix(Rule::multiply, Assoc::Left) | Op::infix(Rule::divide, Assoc::Left))
-			.op(Op::infix(Rule::power, Assoc::Right))
-			.op(Op::postfix(Rule::factorial))
-			.op(Op::prefix(Rule::negate));
+		Ok(())
+	}
 
-		Ok(match pair.as_rule() {
-			Rule::expr => self.exec(pair.into_inner().next().unwrap())?,
-			Rule::assign => {
-				let mut pair = pair.into_inner();
-				let ident = pair.next().unwrap();
-				let expr = pair.next().unwrap();
-				let expr = self.exec(expr)?;
+	pub fn timings(&self) -> Timings {
+		self.timings
+	}
 
-				self.global_vars.insert(ident.as_str().to_string(), expr.clone());
+	/// Warnings collected while generating the program.
+	pub fn warnings(&self) -> &[String] {
+		self.gcode.warnings()
+	}
 
-				expr
+	/// Handles a break, continue or return at the end of a loop iteration, returning true if the loop should stop.
+	fn end_iteration(&mut self) -> bool {
+		match self.control_flow {
+			Some(ControlFlow::Break) => {
+				self.control_flow = None;
+				true
 			},
-			Rule::mathExpr => pratt
-				.map_primary(|primary| self.exec(primary))
-				.map_prefix(|op, rhs| {
-					let rhs = rhs?;
-
-					Ok(match op.as_rule() {
-						Rule::negate => -rhs,
-						_ => unreachable!(),
-					})
-				})
-				.map_postfix(|lhs, op| {
-					let lhs = lhs?;
-
-					Ok(match op.as_rule() {
-						Rule::factorial => lhs.factorial(),
-						_ => unreachable!(),
-					})
-				})
-				.map_infix(|lhs, op, rhs| {
-					let lhs = lhs?;
-					let rhs = rhs?;
-
-					Ok(match op.as_rule() {
-						Rule::add => lhs + rhs,
-						Rule::subtract => lhs - rhs,
-						Rule::multiply => lhs * rhs,
-						Rule::divide => lhs / rhs,
-						Rule::power => lhs.pow(&rhs),
-						_ => unreachable!(),
-					})
-				})
-				.parse(pair.into_inner())?,
-			Rule::string => {
-				let str = &pair.as_str();
-				let str = &str[1..str.len() - 1];
-				let str = str.replace("''", "'");
-				ScriptValue::String(str)
+			Some(ControlFlow::Continue) => {
+				self.control_flow = None;
+				false
 			},
-			Rule::funcCall => {
-				let span = pair.as_span();
-				let mut pair = pair.into_inner();
-				let ident = pair.next().unwrap();
-				let ident_span = ident.as_span();
-				let ident = ident.as_str();
-				let (args, nargs) = self.parse_func_parameters(pair.next().unwrap())?;
-				let ret = self
-					.call_builtin(ident, &args, &nargs)
-					.map_err(|e| pest::error::Error::new_from_span(pest::error::ErrorVariant::<()>::CustomError { message: e.to_string() }, span))?;
-
-				if let Some(ret) = ret {
-					ret
-				} else {
-					return Err(pest::error::Error::new_from_span(
-						pest::error::ErrorVariant::<()>::CustomError {
-							message: "Function not found".to_string(),
+			Some(ControlFlow::Return(_)) => true,
+			None => false,
+		}
+	}
+
+	/// Runs stmts in a new scope, so that their let variables end with the block.
+	fn exec_block(&mut self, stmts: &[Stmt]) -> Result<()> {
+		self.scopes.push(Scope::new());
+		let result = self.exec_stmts(stmts);
+		self.scopes.pop();
+
+		result
+	}
+
+	fn exec_stmts(&mut self, stmts: &[Stmt]) -> Result<()> {
+		for stmt in stmts {
+			self.exec(stmt)?;
+
+			// Skip the rest of the block until the enclosing loop or function call handles the jump
+			if self.control_flow.is_some() {
+				break;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn exec(&mut self, stmt: &Stmt) -> Result<()> {
+		match stmt {
+			Stmt::Expr(expr) => {
+				self.eval(expr)?;
+			},
+			Stmt::For { variables, iterable, body } => {
+				let items = self.eval(iterable)?.into_items().map_err(|e| iterable.span.error(e.to_string()))?;
+
+				self.loop_depth += 1;
+				self.loops.push((0, items.size_hint().0));
+
+				for (i, item) in items.enumerate() {
+					self.loops.last_mut().unwrap().0 = i;
+					self.gcode.cancellation.check()?;
+					self.step(&iterable.span)?;
+
+					let values = match item {
+						item if variables.len() == 1 => vec![item],
+						ScriptValue::List(items) if items.len() == variables.len() => items,
+						item => {
+							let what = match &item {
+								ScriptValue::List(items) => format!("a list of {} items", items.len()),
+								item => format!("a {}", item.type_name()),
+							};
+							return Err(iterable.span.error(format!("Cannot unpack {} into {} loop variables", what, variables.len())));
 						},
-						ident_span,
-					)
-					.into());
+					};
+
+					// Each iteration gets a fresh scope holding the loop variables, so they can't clobber an outer variable
+					let scope = variables
+						.iter()
+						.zip(values)
+						.map(|(name, value)| (name.clone(), Variable { value, constant: None }));
+					self.scopes.push(scope.collect());
+					let result = self.exec_stmts(body);
+					self.scopes.pop();
+					result?;
+
+					if self.end_iteration() {
+						break;
+					}
 				}
+
+				self.loops.pop();
+				self.loop_depth -= 1;
 			},
-			Rule::unitless_number => {
-				let mut pair = pair.into_inner();
-				let value = pair.next().unwrap();
-				let value = match value.as_rule() {
-					Rule::integer => Number::from_int(value.as_str().parse::<i64>().unwrap()),
-					Rule::decimal => Number::from_float(value.as_str().parse::<f64>().unwrap()),
-					_ => unreachable!(),
+			Stmt::Repeat { count, body } => {
+				let times = match self.eval(count)? {
+					ScriptValue::Number(Number {
+						value: InnerValue::Integer(times),
+						unit: Unit::None,
+					}) if times >= 0 => times,
+					_ => return Err(count.span.error("repeat count must be a unitless integer of at least 0")),
 				};
 
-				ScriptValue::Number(value)
+				self.loop_depth += 1;
+				self.loops.push((0, times as usize));
+
+				for i in 0..times as usize {
+					self.loops.last_mut().unwrap().0 = i;
+					self.gcode.cancellation.check()?;
+					self.step(&count.span)?;
+
+					self.exec_block(body)?;
+
+					if self.end_iteration() {
+						break;
+					}
+				}
+
+				self.loops.pop();
+				self.loop_depth -= 1;
 			},
-			Rule::unit_number => {
-				let mut pair = pair.into_inner();
-				let value = pair.next().unwrap();
-				let unit = pair.next().unwrap();
-				let value = match value.as_rule() {
-					Rule::integer => Number::from_int_and_unit(value.as_str().parse().unwrap(), unit.as_str()),
-					Rule::decimal => Number::from_float_and_unit(value.as_str().parse().unwrap(), unit.as_str()),
-					_ => unreachable!(),
+			Stmt::If { condition, body, else_body } => {
+				if self.eval(condition)?.is_truthy() {
+					self.exec_block(body)?;
+				} else if let Some(else_body) = else_body {
+					self.exec_block(else_body)?;
+				}
+			},
+			Stmt::Match { value, arms } => {
+				let subject = self.eval(value)?;
+				let mut matched = None;
+
+				'arms: for arm in arms {
+					if arm.patterns.is_empty() {
+						matched = Some(arm);
+						break;
+					}
+
+					for pattern in &arm.patterns {
+						// Values of different types never match, rather than being an error
+						if self.eval(pattern)?.compare(&subject).is_ok_and(|ordering| ordering == Ordering::Equal) {
+							matched = Some(arm);
+							break 'arms;
+						}
+					}
+				}
+
+				match matched {
+					Some(arm) => self.exec_block(&arm.body)?,
+					None => return Err(value.span.error(format!("No match arm for {}; add a _ arm to ignore other values", subject))),
+				}
+			},
+			Stmt::Break(span) | Stmt::Continue(span) => {
+				let (keyword, control) = match stmt {
+					Stmt::Break(_) => ("break", ControlFlow::Break),
+					_ => ("continue", ControlFlow::Continue),
 				};
 
-				ScriptValue::Number(value)
+				if self.loop_depth == 0 {
+					return Err(span.error(format!("{} outside of a loop", keyword)));
+				}
+
+				self.control_flow = Some(control);
 			},
-			Rule::ident => {
-				let ident = pair.as_str();
-				if let Some(value) = self.global_vars.get(ident) {
-					value.clone()
-				} else {
-					return Err(pest::error::Error::new_from_span(
-						pest::error::ErrorVariant::<()>::CustomError {
-							message: "Variable not found".to_string(),
-						},
-						pair.as_span(),
-					)
-					.into());
+			Stmt::Return(value) => {
+				let value = match value {
+					Some(value) => self.eval(value)?,
+					None => ScriptValue::Null,
+				};
+
+				self.control_flow = Some(ControlFlow::Return(value));
+			},
+			Stmt::Let {
+				variable,
+				value,
+				constant,
+				span,
+			} => {
+				let value = self.eval(value)?;
+				self.declare(variable, value, *constant, span)?;
+			},
+			Stmt::Param { variable, default, span } => {
+				let value = match self.params.get(variable) {
+					Some(value) => value.clone(),
+					None => self.eval(default)?,
+				};
+
+				self.declared_params.insert(variable.clone());
+				self.declare(variable, value, true, span)?;
+			},
+			Stmt::Global { variable, value, span } => {
+				let value = self.eval(value)?;
+
+				assign(&mut self.global_vars, variable, value).map_err(|e| span.error(e))?;
+			},
+			Stmt::Destructure { variables, value, span } => {
+				let items = match self.eval(value)? {
+					ScriptValue::List(items) => items,
+					value => return Err(span.error(format!("Cannot unpack a {} into {} variables", value.type_name(), variables.len()))),
+				};
+
+				if items.len() != variables.len() {
+					return Err(span.error(format!("Cannot unpack a list of {} items into {} variables", items.len(), variables.len())));
+				}
+
+				for (variable, item) in variables.iter().zip(items) {
+					self.assign(variable, item).map_err(|e| span.error(e))?;
 				}
 			},
-			Rule::forLoop => {
-				let mut pair = pair.into_inner();
-				let loop_variable = pair.next().unwrap().as_str();
-				let range = pair.next().unwrap();
-				let range_span = range.as_span();
-				let range = self.exec(range)?;
-				let block = pair.next().unwrap();
-
-				if let ScriptValue::Range { start, step, num } = range {
-					for i in 0..num {
-						self.global_vars
-							.insert(loop_variable.to_string(), ScriptValue::Number(start + step * (i as i64).into()));
-						self.exec(block.clone())?;
-					}
+			Stmt::Function(function) => {
+				self.functions.insert(function.name.clone(), function.clone());
+			},
+			Stmt::Include { path, span } => {
+				// Relative paths are relative to the including file, or the working directory for scripts that aren't files
+				let path = match self.include_stack.last().and_then(|file| file.parent()) {
+					Some(dir) => dir.join(path),
+					None => PathBuf::from(path),
+				};
+				let (source, canonical) = read_script(&path).map_err(|e| span.error(format!("{:#}", e)))?;
+
+				if let Some(i) = self.include_stack.iter().position(|file| *file == canonical) {
+					let cycle = self.include_stack[i..]
+						.iter()
+						.chain([&canonical])
+						.map(|file| file.display().to_string())
+						.collect::<Vec<_>>();
+					return Err(span.error(format!("Include cycle: {}", cycle.join(" -> "))));
+				}
+
+				let program = self.parse(source, false)?;
+
+				self.include_stack.push(canonical);
+				let result = self.exec_stmts(&program);
+				self.include_stack.pop();
+				result?;
+
+				// A return at the top level of an included file only ends that file
+				if let Some(ControlFlow::Return(_)) = self.control_flow {
+					self.control_flow = None;
+				}
+			},
+		}
+
+		Ok(())
+	}
+
+	/// Creates a variable in the current block, hiding any outer variable of the same name.
+	fn declare(&mut self, name: &str, value: ScriptValue, constant: bool, span: &Span) -> Result<()> {
+		let scope = self.scopes.last_mut().unwrap_or(&mut self.global_vars);
+
+		if let Some(declared) = scope.get(name).and_then(|variable| variable.constant.as_ref()) {
+			return Err(span.error(format!("{} is already declared as a constant at {}", name, declared.location())));
+		}
+
+		scope.insert(
+			name.to_string(),
+			Variable {
+				value,
+				constant: constant.then(|| span.clone()),
+			},
+		);
+
+		Ok(())
+	}
+
+	/// Assigning updates the innermost variable with the name.  A new name is created for the whole function, or as a global
+	/// outside of functions, so only `let` makes a variable local to a block.  Inside a function only `global` writes to globals.
+	fn assign(&mut self, name: &str, value: ScriptValue) -> Result<(), String> {
+		let scope = match self.scopes.iter().rposition(|scope| scope.contains_key(name)) {
+			Some(i) => &mut self.scopes[i],
+			None if self.call_depth == 0 => &mut self.global_vars,
+			// The first scope of a function call holds its parameters
+			None => self.scopes.first_mut().unwrap_or(&mut self.global_vars),
+		};
+
+		assign(scope, name, value)
+	}
+
+	fn eval(&mut self, expr: &Expr) -> Result<ScriptValue> {
+		Ok(match &expr.kind {
+			ExprKind::Literal(value) => value.clone(),
+			ExprKind::Assign(ident, value) => {
+				let value = self.eval(value)?;
+				self.assign(ident, value.clone()).map_err(|e| expr.span.error(e))?;
+
+				value
+			},
+			ExprKind::List(items) => ScriptValue::List(items.iter().map(|item| self.eval(item)).collect::<Result<_>>()?),
+			ExprKind::Call {
+				name,
+				name_span,
+				args,
+				named_args,
+			} => {
+				let args = args.iter().map(|arg| self.eval(arg)).collect::<Result<Vec<_>>>()?;
+				let named_args = named_args
+					.iter()
+					.map(|(name, arg)| Ok((name.clone(), self.eval(arg)?)))
+					.collect::<Result<HashMap<_, _>>>()?;
+
+				self.gcode.cancellation.check()?;
+
+				// A user function, or a variable holding one, hides a builtin of the same name
+				let function = match self.variable(name) {
+					Some(ScriptValue::Function(function)) => Some(function.clone()),
+					_ if self.functions.contains_key(name) => Some(name.clone()),
+					_ => None,
+				};
+				if let Some(function) = function {
+					return self.call_function(&function, args, named_args, &expr.span);
+				}
+
+				let fraction = self.progress_fraction();
+				if let Some(callback) = &mut self.progress_callback {
+					callback(&Progress {
+						completed: self.statements_completed,
+						total: self.statements_total,
+						fraction,
+						builtin: name,
+					});
+				}
+
+				let start = Instant::now();
+				self.call_span = Some(expr.span.clone());
+				self.builtin_depth += 1;
+				let ret = self.call_builtin(name, args, named_args);
+				self.builtin_depth -= 1;
+				if self.builtin_depth == 0 {
+					self.timings.builtins += start.elapsed();
+				}
+
+				match ret.map_err(|e| expr.span.locate(e))? {
+					Some(ret) => ret,
+					None => return Err(name_span.error("Function not found")),
+				}
+			},
+			ExprKind::Variable(ident) => {
+				if let Some(value) = self.variable(ident) {
+					value.clone()
+				} else if self.functions.contains_key(ident) {
+					ScriptValue::Function(ident.clone())
+				} else if let Some(value) = self.builtin_variable(ident).map_err(|e| expr.span.error(e.to_string()))? {
+					value
 				} else {
-					return Err(pest::error::Error::new_from_span(
-						pest::error::ErrorVariant::<()>::CustomError {
-							message: "Expected range".to_string(),
-						},
-						range_span,
-					)
-					.into());
+					return Err(expr.span.error("Variable not found"));
 				}
+			},
+			ExprKind::GlobalVariable(ident) => match self.global_vars.get(ident) {
+				Some(variable) => variable.value.clone(),
+				None => return Err(expr.span.error("Global variable not found")),
+			},
+			ExprKind::Member(ident) => match self.builtin_variable(ident).map_err(|e| expr.span.error(e.to_string()))? {
+				Some(value) => value,
+				None => return Err(expr.span.error("Unknown value")),
+			},
+			ExprKind::Unary(op, operand) => {
+				let operand = self.eval(operand)?;
 
-				ScriptValue::Null
+				match op {
+					UnaryOp::Negate => -operand,
+					UnaryOp::Not => Ok(ScriptValue::Bool(!operand.is_truthy())),
+					UnaryOp::Factorial => operand.factorial(),
+				}
+				.map_err(|e| expr.span.error(e.to_string()))?
+			},
+			// and, or and ?? only evaluate their right side when it decides the result
+			ExprKind::Binary(BinaryOp::And, lhs, rhs) => ScriptValue::Bool(self.eval(lhs)?.is_truthy() && self.eval(rhs)?.is_truthy()),
+			ExprKind::Binary(BinaryOp::Or, lhs, rhs) => ScriptValue::Bool(self.eval(lhs)?.is_truthy() || self.eval(rhs)?.is_truthy()),
+			ExprKind::Binary(BinaryOp::Coalesce, lhs, rhs) => match self.eval(lhs)? {
+				ScriptValue::Null => self.eval(rhs)?,
+				value => value,
 			},
-			Rule::block => {
-				for pair in pair.into_inner() {
-					self.exec(pair)?;
+			ExprKind::Binary(op, lhs, rhs) => {
+				let lhs = self.eval(lhs)?;
+				let rhs = self.eval(rhs)?;
+
+				match op {
+					BinaryOp::Add => lhs + rhs,
+					BinaryOp::Subtract => lhs - rhs,
+					BinaryOp::Multiply => lhs * rhs,
+					BinaryOp::Divide => lhs / rhs,
+					BinaryOp::Power => lhs.pow(&rhs),
+					BinaryOp::Equal => lhs.compare(&rhs).map(|ordering| ScriptValue::Bool(ordering == Ordering::Equal)),
+					BinaryOp::NotEqual => lhs.compare(&rhs).map(|ordering| ScriptValue::Bool(ordering != Ordering::Equal)),
+					BinaryOp::Less => lhs.compare(&rhs).map(|ordering| ScriptValue::Bool(ordering == Ordering::Less)),
+					BinaryOp::LessEqual => lhs.compare(&rhs).map(|ordering| ScriptValue::Bool(ordering != Ordering::Greater)),
+					BinaryOp::Greater => lhs.compare(&rhs).map(|ordering| ScriptValue::Bool(ordering == Ordering::Greater)),
+					BinaryOp::GreaterEqual => lhs.compare(&rhs).map(|ordering| ScriptValue::Bool(ordering != Ordering::Less)),
+					BinaryOp::And | BinaryOp::Or | BinaryOp::Coalesce => unreachable!(),
 				}
+				.map_err(|e| expr.span.error(e.to_string()))?
+			},
+			ExprKind::Range { start, stop, step, inclusive } => {
+				let start = self.eval(start)?;
+				let stop = self.eval(stop)?;
+				let step = step.as_ref().map(|step| self.eval(step)).transpose()?;
 
-				ScriptValue::Null
+				ScriptValue::range(start, stop, step, *inclusive).map_err(|e| expr.span.error(e.to_string()))?
+			},
+			ExprKind::Index(list, index) => {
+				let list = self.eval(list)?;
+				let index = self.eval(index)?;
+
+				list.index(&index).map_err(|e| expr.span.error(e.to_string()))?
 			},
-			unknown_expr => panic!("Unexpected expr: {:?}", unknown_expr),
 		})
 	}
+}
+
 
-	fn parse_func_parameters(&mut self, pair: pest::iterators::Pair<Rule>) -> Result<(Vec<ScriptValue>, HashMap<String, ScriptValue>)> {
-		let mut positional_args = Vec::new();
-		let mut named_args = HashMap::new();
-
-		for arg in pair.into_inner() {
-			match arg.as_rule() {
-				Rule::positionalParam => positional_args.push(self.exec(arg.into_inner().next().unwrap())?),
-				Rule::namedParam => {
-					let mut pair = arg.into_inner();
-					let ident = pair.next().unwrap();
-					let expr = pair.next().unwrap();
-					named_args.insert(ident.as_str().to_string(), self.exec(expr)?);
-				},
-				_ => unreachable!(),
+impl ScriptEngine {
+	fn variable(&self, name: &str) -> Option<&ScriptValue> {
+		self.scopes
+			.iter()
+			.rev()
+			.chain([&self.global_vars])
+			.find_map(|scope| scope.get(name))
+			.map(|variable| &variable.value)
+	}
+
+	/// Top level statements done, plus the part of the current one done according to the loops being run.
+	fn progress_fraction(&self) -> f64 {
+		let current = self.loops.iter().rev().fold(0.0, |inner, &(i, count)| (i as f64 + inner) / count.max(1) as f64);
+
+		((self.statements_completed as f64 + current.min(1.0)) / self.statements_total.max(1) as f64).min(1.0)
+	}
+
+	/// Counts a loop iteration or function call against max_steps.
+	fn step(&mut self, span: &Span) -> Result<()> {
+		self.steps += 1;
+
+		match self.max_steps {
+			Some(max_steps) if self.steps > max_steps => {
+				Err(span.error(format!("Script exceeded the limit of {} loop iterations and function calls", max_steps)))
+			},
+			_ => Ok(()),
+		}
+	}
+
+	/// Runs a user function with its own variables, which can see globals but not the caller's locals.
+	pub(super) fn call_function(&mut self, name: &str, args: Vec<ScriptValue>, named_args: HashMap<String, ScriptValue>, span: &Span) -> Result<ScriptValue> {
+		let function = self
+			.functions
+			.get(name)
+			.cloned()
+			.ok_or_else(|| span.error(format!("Function {} is not defined", name)))?;
+
+		if self.call_depth >= self.max_call_depth {
+			return Err(span.error(format!(
+				"Maximum call depth of {} exceeded; check that recursion in {} stops",
+				self.max_call_depth, function.name
+			)));
+		}
+
+		self.step(span)?;
+
+		self.call_depth += 1;
+		let result = self.run_function(&function, args, named_args);
+		self.call_depth -= 1;
+
+		result.map_err(|e| span.locate(e))
+	}
+
+	fn run_function(&mut self, function: &Function, args: Vec<ScriptValue>, mut named_args: HashMap<String, ScriptValue>) -> Result<ScriptValue> {
+		if args.len() > function.params.len() {
+			bail!("{}: too many arguments, expected {}, got {}", function.name, function.params.len(), args.len());
+		}
+
+		let mut args = args.into_iter();
+
+		// Defaults are evaluated in the function's scope, so they can refer to earlier parameters
+		let caller_scopes = std::mem::replace(&mut self.scopes, vec![Scope::new()]);
+		let caller_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+
+		let result = (|| {
+			for (name, default) in &function.params {
+				let value = match (args.next(), named_args.remove(name), default) {
+					(Some(_), Some(_), _) => bail!("{}: {} was given twice", function.name, name),
+					(Some(value), None, _) | (None, Some(value), _) => value,
+					(None, None, Some(default)) => self.eval(default)?,
+					(None, None, None) => bail!("{}: {} is required", function.name, name),
+				};
+
+				self.scopes[0].insert(name.clone(), Variable { value, constant: None });
+			}
+
+			if let Some(name) = named_args.keys().next() {
+				bail!("{}: unknown named argument {}", function.name, name);
 			}
+
+			self.exec_stmts(&function.body)
+		})();
+
+		self.scopes = caller_scopes;
+		self.loop_depth = caller_loop_depth;
+
+		match self.control_flow.take() {
+			Some(ControlFlow::Return(value)) => result.map(|_| value),
+			_ => result.map(|_| ScriptValue::Null),
 		}
+	}
+}
+
 
-		Ok((positional_args, named_args))
+/// Sets a variable in scope, creating it if needed, unless it is a constant.
+fn assign(scope: &mut Scope, name: &str, value: ScriptValue) -> Result<(), String> {
+	match scope.get_mut(name) {
+		Some(Variable { constant: Some(declared), .. }) => Err(format!("Cannot assign to constant {} declared at {}", name, declared.location())),
+		Some(variable) => {
+			variable.value = value;
+			Ok(())
+		},
+		None => {
+			scope.insert(name.to_string(), Variable { value, constant: None });
+			Ok(())
+		},
 	}
 }
 
 
+/// Reads a script file, returning its source and canonical path.
+fn read_script(path: &Path) -> Result<(Source, PathBuf)> {
+	let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+	let canonical = path.canonicalize().with_context(|| format!("Failed to read file: {}", path.display()))?;
+	let source = Source {
+		text,
+		path: Some(path.display().to_string()),
+	};
+
+	Ok((source, canonical))
+}
+
+
 impl Default for ScriptEngine {
 	fn default() -> Self {
 		Self::new()
@@ -270,6 +935,7 @@ impl Default for ScriptEngine {
 }
 
 
+#[derive(Clone)]
 struct Material {
 	stepover: f64,
 	depth_per_pass: f64,
@@ -277,3 +943,281 @@ struct Material {
 	plunge_rate: f64,
 	rpm: f64,
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn global(engine: &ScriptEngine, name: &str) -> Option<String> {
+		engine.global_vars.get(name).map(|variable| variable.value.to_string())
+	}
+
+	#[test]
+	fn assignment_in_function_is_local_to_each_call() {
+		let mut engine = ScriptEngine::new();
+		engine
+			.run(
+				"seen = []\nfn f(n) {\n\ttmp = n\n\tif n > 0 { f(n - 1) }\n\tglobal seen = append(seen, tmp)\n}\nf(2)\n",
+				false,
+			)
+			.unwrap();
+
+		assert_eq!(global(&engine, "seen").as_deref(), Some("[0, 1, 2]"));
+		assert_eq!(global(&engine, "tmp"), None);
+		assert!(engine.run("print(tmp)", false).is_err());
+	}
+
+	#[test]
+	fn assignment_in_function_does_not_write_globals() {
+		let mut engine = ScriptEngine::new();
+		engine.run("x = 1\nfn f() {\n\tx = 2\n\treturn x\n}\ny = f()\n", false).unwrap();
+
+		assert_eq!(global(&engine, "x").as_deref(), Some("1"));
+		assert_eq!(global(&engine, "y").as_deref(), Some("2"));
+
+		engine.run("fn g() {\n\tglobal x = 3\n}\ng()\n", false).unwrap();
+		assert_eq!(global(&engine, "x").as_deref(), Some("3"));
+	}
+
+	#[test]
+	fn assignment_in_block() {
+		let mut engine = ScriptEngine::new();
+		engine
+			.run("total = 0\nfor i in [1, 2, 3] {\n\ttotal = total + i\n\tlast = i\n\tlet temp = i\n}\n", false)
+			.unwrap();
+
+		assert_eq!(global(&engine, "total").as_deref(), Some("6"));
+		assert_eq!(global(&engine, "last").as_deref(), Some("3"));
+		assert_eq!(global(&engine, "temp"), None);
+		assert_eq!(global(&engine, "i"), None);
+	}
+
+	#[test]
+	fn assignment_in_if_else() {
+		let mut engine = ScriptEngine::new();
+		engine
+			.run("total = 3\nif total > 2 {\n\tpasses = 3\n} else {\n\tpasses = 2\n}\nresult = passes\n", false)
+			.unwrap();
+		assert_eq!(global(&engine, "result").as_deref(), Some("3"));
+
+		let mut engine = ScriptEngine::new();
+		let script = "fn f(n) {\n\tif n > 2 {\n\t\tpasses = 3\n\t} else {\n\t\tpasses = 2\n\t}\n\treturn passes\n}\nresult = f(1)\n";
+		engine.run(script, false).unwrap();
+		assert_eq!(global(&engine, "result").as_deref(), Some("2"));
+		assert_eq!(global(&engine, "passes"), None);
+	}
+
+	#[test]
+	fn string_literals() {
+		let mut engine = ScriptEngine::new();
+		engine.run("a = 'C:\\temp\\x'\nb = 'it''s'\nc = \"tab\\there\"\n", false).unwrap();
+
+		assert_eq!(global(&engine, "a").as_deref(), Some("C:\\temp\\x"));
+		assert_eq!(global(&engine, "b").as_deref(), Some("it's"));
+		assert_eq!(global(&engine, "c").as_deref(), Some("tab\there"));
+	}
+
+	#[test]
+	fn runaway_recursion_is_an_error() {
+		// The main thread's stack size, which the default limit must fit in even in debug builds
+		let thread = std::thread::Builder::new().stack_size(8 * 1024 * 1024).spawn(|| {
+			let mut engine = ScriptEngine::new();
+			let error = engine.run("fn f(n) {\n\tif n >= 0 {\n\t\treturn f(n + 1)\n\t}\n}\nf(0)\n", false).unwrap_err();
+
+			error.to_string()
+		});
+
+		assert!(thread.unwrap().join().unwrap().contains("Maximum call depth of 100 exceeded"));
+	}
+
+	#[test]
+	fn progress_advances_inside_loops() {
+		let fractions = Rc::new(std::cell::RefCell::new(Vec::new()));
+		let mut engine = ScriptEngine::new();
+		let recorded = fractions.clone();
+		engine.set_progress_callback(move |progress| recorded.borrow_mut().push(progress.fraction));
+
+		engine
+			.run("for i in 0..2 {\n\trepeat(2) {\n\t\tcomment('x')\n\t}\n}\ncomment('done')\n", false)
+			.unwrap();
+
+		assert_eq!(*fractions.borrow(), [0.0, 0.125, 0.25, 0.375, 0.5]);
+	}
+
+	#[test]
+	fn for_loop_variables() {
+		let mut engine = ScriptEngine::new();
+		engine.run("a = []\nfor (x) in [1, 2] {\n\ta = append(a, x)\n}\n", false).unwrap();
+		engine
+			.run("b = []\nfor (i, x) in enumerate([5, 6]) {\n\tb = append(b, i + x)\n}\n", false)
+			.unwrap();
+
+		assert_eq!(global(&engine, "a").as_deref(), Some("[1, 2]"));
+		assert_eq!(global(&engine, "b").as_deref(), Some("[5, 7]"));
+	}
+
+	#[test]
+	fn errors_through_builtins_are_located_once() {
+		let mut engine = ScriptEngine::new();
+		let error = engine
+			.run("fn f(x) {\n\treturn map([x], g)\n}\nfn g(x) {\n\treturn x + true\n}\nmap([1], f)\n", false)
+			.unwrap_err()
+			.to_string();
+
+		assert_eq!(error.matches("-->").count(), 1, "{}", error);
+		assert!(error.contains("5:9"), "{}", error);
+	}
+
+	#[test]
+	fn fraction_literals() {
+		let mut engine = ScriptEngine::new();
+		engine.run("a = 3/8in\nb = 1 1/2in\nc = 10in - 3/8in\n", false).unwrap();
+
+		assert_eq!(global(&engine, "a").as_deref(), Some("0.375in"));
+		assert_eq!(global(&engine, "b").as_deref(), Some("1.5in"));
+		assert_eq!(global(&engine, "c").as_deref(), Some("9.625in"));
+
+		let error = engine.run("d = 10-3/8in\n", false).unwrap_err().to_string();
+		assert!(error.contains("10-3/8in is ambiguous"), "{}", error);
+	}
+
+	#[test]
+	fn includes() {
+		let dir = std::env::temp_dir().join(format!("gcad-includes-{}", std::process::id()));
+		std::fs::create_dir_all(dir.join("lib")).unwrap();
+		std::fs::write(dir.join("main.gcad"), "include 'lib/parts.gcad'\nwidth = part_width()\n").unwrap();
+		std::fs::write(dir.join("lib/parts.gcad"), "fn part_width() {\n\treturn 20mm\n}\n").unwrap();
+		std::fs::write(dir.join("a.gcad"), "include 'lib/b.gcad'\n").unwrap();
+		std::fs::write(dir.join("lib/b.gcad"), "include '../a.gcad'\n").unwrap();
+
+		let mut engine = ScriptEngine::new();
+		let result = engine.run_file(dir.join("main.gcad"), false);
+		let cycle = ScriptEngine::new().run_file(dir.join("a.gcad"), false);
+		std::fs::remove_dir_all(&dir).unwrap();
+
+		result.unwrap();
+		assert_eq!(global(&engine, "width").as_deref(), Some("20mm"));
+
+		let error = cycle.unwrap_err().to_string();
+		assert!(error.contains("Include cycle"), "{}", error);
+		assert!(error.contains("a.gcad -> "), "{}", error);
+	}
+
+	#[test]
+	fn match_statement() {
+		let mut engine = ScriptEngine::new();
+		let script = "fn kind(x) {
+	match x {
+		1 | 2 => { return 'small' }
+		'1' => { return 'string' }
+		3 => { return 'three' }, _ => { return 'other' }
+	}
+}
+fn exact(x) {
+	match x {
+		25.4mm => { return 'inch' }
+	}
+}
+a = [kind(1), kind(2), kind('1'), kind(true), kind(3)]
+b = exact(1in)
+";
+		engine.run(script, false).unwrap();
+
+		assert_eq!(global(&engine, "a").as_deref(), Some(r#"["small", "small", "string", "other", "three"]"#));
+		assert_eq!(global(&engine, "b").as_deref(), Some("inch"));
+
+		let error = engine.run("exact(2in)", false).unwrap_err().to_string();
+		assert!(error.contains("No match arm for 2in"), "{}", error);
+	}
+
+	fn generate(engine: &mut ScriptEngine, script: &str) -> String {
+		engine.write_header();
+		engine.run(script, false).unwrap();
+		let mut output = Vec::new();
+		engine.finish(&mut output).unwrap();
+		String::from_utf8(output).unwrap()
+	}
+
+	#[test]
+	fn reset_generates_the_same_program_again() {
+		let script = "cutter_diameter(3mm)\nfn hole(x) {\n\tdrill(x, 5mm, depth=2mm)\n}\nfor x in [5mm, 10mm] {\n\thole(x)\n}\nlast = 10mm\n";
+		let mut engine = ScriptEngine::new();
+		let first = generate(&mut engine, script);
+
+		engine.reset();
+		assert_eq!(global(&engine, "last"), None);
+		assert!(engine.run("hole(1mm)", false).is_err());
+
+		engine.reset();
+		assert_eq!(generate(&mut engine, script), first);
+		assert_eq!(generate(&mut ScriptEngine::new(), script), first);
+	}
+
+	#[test]
+	fn restore_rolls_back_to_snapshot() {
+		let start = "cutter_diameter(3mm)\ndepth = 2mm\nfn hole(x) {\n\tdrill(x, 5mm, depth=depth)\n}\nhole(5mm)\n";
+		let end = "hole(10mm)\n";
+		let mut engine = ScriptEngine::new();
+		engine.write_header();
+		engine.run(start, false).unwrap();
+		let snapshot = engine.snapshot();
+
+		let discarded = "depth = 4mm\ncutter_diameter(6mm)\nfn hole(x) {}\nextra = 1\ndrill(20mm, 5mm, depth=1mm)\n";
+		engine.run(discarded, false).unwrap();
+		engine.restore(&snapshot);
+		assert_eq!(global(&engine, "extra"), None);
+		engine.run(end, false).unwrap();
+		let mut output = Vec::new();
+		engine.finish(&mut output).unwrap();
+
+		let mut fresh = ScriptEngine::new();
+		assert_eq!(String::from_utf8(output).unwrap(), generate(&mut fresh, &format!("{}{}", start, end)));
+	}
+
+	#[test]
+	fn halftone_drill() {
+		let path = std::env::temp_dir().join(format!("gcad-halftone-{}.pgm", std::process::id()));
+		// Black, mid gray and white columns
+		std::fs::write(&path, "P2\n3 1\n2\n0 1 2\n").unwrap();
+
+		let script = format!("halftone_drill('{}', 0mm, 0mm, 30mm, 10mm, 10mm, max_depth=2mm)\n", path.display());
+		let mut engine = ScriptEngine::new();
+		let output = generate(&mut engine, &script);
+		std::fs::remove_file(&path).unwrap();
+
+		// Darker pixels drill deeper, and white ones are skipped
+		assert!(output.contains("X5 Y5\nZ0.25\nG1 Z-2 "), "{}", output);
+		assert!(output.contains("X15\nG1 Z-1\nG0 Z0.25\nZ5\n"), "{}", output);
+		assert!(!output.contains("X25"), "{}", output);
+	}
+
+	#[test]
+	fn positioning_helpers() {
+		let mut engine = ScriptEngine::new();
+		engine.run("stock(100mm, 50mm)\ncorner = center_rect(50mm, 25mm, 20mm, 1in)\n", false).unwrap();
+		engine
+			.run("right = from_edge('right', 10mm)\nleft = from_edge('right', 10mm, size=20mm)\n", false)
+			.unwrap();
+
+		assert_eq!(global(&engine, "corner").as_deref(), Some("[40mm, 12.3mm]"));
+		assert_eq!(global(&engine, "right").as_deref(), Some("90mm"));
+		assert_eq!(global(&engine, "left").as_deref(), Some("70mm"));
+		assert!(engine.run("center_rect(0mm, 0mm, 1mm, 1deg)", false).is_err());
+	}
+
+	#[test]
+	fn random() {
+		let mut engine = ScriptEngine::new();
+		let script = "a = random(1mm, 1in, seed=7)\nb = rand(1mm, 1in, seed=7)\nc = random(10deg, 20deg)\nd = random(0, 1)\n";
+		engine.run(script, false).unwrap();
+
+		assert_eq!(global(&engine, "a"), global(&engine, "b"));
+		for (name, unit) in [("a", "mm"), ("c", "deg")] {
+			let value = global(&engine, name).unwrap();
+			assert!(value.ends_with(unit), "{} = {}", name, value);
+		}
+		assert!(global(&engine, "d").unwrap().parse::<f64>().is_ok());
+		assert!(engine.run("random(1mm, 1deg)", false).is_err());
+	}
+}
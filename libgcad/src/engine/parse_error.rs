@@ -0,0 +1,214 @@
+use std::{iter::Peekable, str::Chars};
+
+use pest::error::{Error, ErrorVariant, InputLocation};
+
+use super::Rule;
+
+
+/// Rewrites pest's list of expected rules into something readable, with a hint for common mistakes.
+pub fn humanize(error: Error<Rule>, source: &str, path: Option<&str>) -> anyhow::Error {
+	let pos = match error.location {
+		InputLocation::Pos(pos) => pos,
+		InputLocation::Span((start, _)) => start,
+	};
+
+	let expected = match &error.variant {
+		ErrorVariant::ParsingError { positives, .. } => describe_expected(positives),
+		ErrorVariant::CustomError { message } => message.clone(),
+	};
+
+	// Line the hint up under pest's "= message" line, which is indented past the line number gutter
+	let message = match hint(source, pos) {
+		Some(hint) => {
+			let line = source[..pos].matches('\n').count() + 1;
+			format!("{}\n{:indent$}= hint: {}", expected, "", hint, indent = line.to_string().len() + 1)
+		},
+		None => expected,
+	};
+
+	let position = pest::Position::new(source, pos).unwrap_or_else(|| pest::Position::from_start(source));
+	let error = Error::<()>::new_from_pos(ErrorVariant::CustomError { message }, position);
+
+	match path {
+		Some(path) => error.with_path(path).into(),
+		None => error.into(),
+	}
+}
+
+
+fn describe_expected(positives: &[Rule]) -> String {
+	let mut expected: Vec<&str> = Vec::new();
+
+	for rule in positives {
+		let description = match rule {
+			Rule::add
+			| Rule::subtract
+			| Rule::multiply
+			| Rule::divide
+			| Rule::power
+			| Rule::lessEqual
+			| Rule::greaterEqual
+			| Rule::equal
+			| Rule::notEqual
+			| Rule::less
+			| Rule::greater
+			| Rule::and
+			| Rule::or
+			| Rule::coalesce
+			| Rule::factorial
+			| Rule::index
+			| Rule::assignOp
+			| Rule::rangeOp => "an operator",
+			Rule::stepKeyword => "step",
+			Rule::unit => "a unit (mm, cm, m, in, ft, yd, deg, rad)",
+			Rule::forLoop
+			| Rule::repeatLoop
+			| Rule::ifStatement
+			| Rule::matchStatement
+			| Rule::breakStatement
+			| Rule::continueStatement
+			| Rule::returnStatement
+			| Rule::includeStatement
+			| Rule::letStatement
+			| Rule::paramStatement
+			| Rule::globalStatement
+			| Rule::functionDef
+			| Rule::destructure
+			| Rule::exprStatement => "a statement",
+			Rule::expr
+			| Rule::rangeExpr
+			| Rule::mathExpr
+			| Rule::list
+			| Rule::negate
+			| Rule::not
+			| Rule::unit_number
+			| Rule::fraction_number
+			| Rule::dashed_fraction
+			| Rule::unitless_number
+			| Rule::boolean
+			| Rule::null
+			| Rule::string
+			| Rule::funcCall
+			| Rule::member
+			| Rule::assign
+			| Rule::funcCallParams
+			| Rule::positionalParam
+			| Rule::namedParam => "an expression",
+			Rule::ident => "a name",
+			Rule::block => "a block ({ ... })",
+			Rule::matchArm | Rule::wildcard => "a match arm (pattern => ...)",
+			Rule::EOI => "the end of the file",
+			_ => "something else",
+		};
+
+		if !expected.contains(&description) {
+			expected.push(description);
+		}
+	}
+
+	match expected.as_slice() {
+		[] => "Syntax error".to_string(),
+		[one] => format!("Expected {}", one),
+		[rest @ .., last] => format!("Expected {} or {}", rest.join(", "), last),
+	}
+}
+
+
+/// Looks at the text around the error for a likely cause.
+fn hint(source: &str, pos: usize) -> Option<String> {
+	let before = &source[..pos];
+	let after = &source[pos..];
+
+	// A number followed by letters that don't form a unit, e.g. 5inch
+	let word_start = before.rfind(|c: char| !c.is_ascii_alphanumeric() && c != '.').map_or(0, |i| i + 1);
+	let word_end = pos + after.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(after.len());
+	let word = &source[word_start..word_end];
+	if word_start < pos && word_end > pos && word.starts_with(|c: char| c.is_ascii_digit()) {
+		if let Some(unit_start) = word.find(|c: char| c.is_ascii_alphabetic()) {
+			return Some(format!("'{}' is not a unit; use one of mm, cm, m, in, ft, yd, deg, rad", &word[unit_start..]));
+		}
+	}
+
+	let mut chars = after.chars().peekable();
+	if let Some(quote @ ('\'' | '"')) = chars.next() {
+		if !skip_string(&mut chars, quote) {
+			return Some("the string is never closed".to_string());
+		}
+	}
+
+	// Unbalanced brackets before the error
+	let mut open = Vec::new();
+	for c in code_chars(before) {
+		match c {
+			'(' | '[' | '{' => open.push(c),
+			')' | ']' | '}' => {
+				open.pop();
+			},
+			_ => {},
+		}
+	}
+
+	let at_line_end = after.trim_start_matches([' ', '\t', '\r']).starts_with('\n') || after.trim().is_empty();
+	match open.last() {
+		Some('(') if at_line_end => return Some("missing a closing )".to_string()),
+		Some('[') if at_line_end => return Some("missing a closing ]".to_string()),
+		Some('{') if after.trim().is_empty() => return Some("missing a closing }".to_string()),
+		Some('(') => return Some("separate arguments with commas".to_string()),
+		_ => {},
+	}
+
+	if at_line_end && before.trim_end().ends_with(['+', '-', '*', '/', '^', '<', '>', '=']) {
+		return Some("the line ends with an operator that is missing its right side".to_string());
+	}
+
+	None
+}
+
+
+/// Characters of source outside of strings and comments.
+fn code_chars(source: &str) -> Vec<char> {
+	let mut chars = source.chars().peekable();
+	let mut code = Vec::new();
+
+	while let Some(c) = chars.next() {
+		match c {
+			'\'' | '"' => {
+				skip_string(&mut chars, c);
+			},
+			'#' => while chars.next_if(|&c| c != '\n').is_some() {},
+			'/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+			'/' if chars.peek() == Some(&'*') => {
+				chars.next();
+				let mut previous = ' ';
+				for c in chars.by_ref() {
+					if previous == '*' && c == '/' {
+						break;
+					}
+					previous = c;
+				}
+			},
+			_ => code.push(c),
+		}
+	}
+
+	code
+}
+
+
+/// Advances past the closing quote of a string, returning false if the string is never closed.
+fn skip_string(chars: &mut Peekable<Chars>, quote: char) -> bool {
+	let mut escaped = false;
+
+	while let Some(c) = chars.next() {
+		if c == quote && !escaped {
+			// A doubled single quote is an escaped quote, not the end of the string
+			if quote == '\'' && chars.next_if_eq(&quote).is_some() {
+				continue;
+			}
+			return true;
+		}
+		escaped = !escaped && c == '\\';
+	}
+
+	false
+}
@@ -0,0 +1,144 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::{numbers::Unit, value::ScriptValue};
+
+
+/// Fills each {} in template with the next value.  A placeholder can give a precision and a unit to convert numbers to,
+/// e.g. {:.2} or {:in} or {:.3mm}.  {{ and }} are literal braces.
+pub fn format(template: &str, values: &[ScriptValue]) -> Result<String> {
+	let mut result = String::new();
+	let mut values = values.iter();
+	let mut chars = template.chars();
+
+	while let Some(c) = chars.next() {
+		match c {
+			'{' if chars.as_str().starts_with('{') => {
+				chars.next();
+				result.push('{');
+			},
+			'}' if chars.as_str().starts_with('}') => {
+				chars.next();
+				result.push('}');
+			},
+			'{' => {
+				let Some((spec, rest)) = chars.as_str().split_once('}') else {
+					bail!("Unclosed {{ in format string");
+				};
+				let Some(value) = values.next() else {
+					bail!("Format string has more placeholders than values");
+				};
+
+				result.push_str(&format_value(value, &Spec::parse(spec)?)?);
+				chars = rest.chars();
+			},
+			'}' => bail!("Unmatched }} in format string; use }}}} for a literal brace"),
+			c => result.push(c),
+		}
+	}
+
+	if values.next().is_some() {
+		bail!("Format string has fewer placeholders than values");
+	}
+
+	Ok(result)
+}
+
+
+#[derive(Default)]
+struct Spec {
+	precision: Option<usize>,
+	unit: Option<Unit>,
+}
+
+impl Spec {
+	fn parse(spec: &str) -> Result<Spec> {
+		let Some(spec) = spec.strip_prefix(':') else {
+			if !spec.is_empty() {
+				bail!("Invalid placeholder {{{}}}; expected {{}} or {{:spec}}", spec);
+			}
+			return Ok(Spec::default());
+		};
+
+		let (precision, unit) = match spec.strip_prefix('.') {
+			Some(rest) => {
+				let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+				match rest[..digits].parse() {
+					Ok(precision) => (Some(precision), &rest[digits..]),
+					Err(_) => bail!("Invalid precision in {{:{}}}", spec),
+				}
+			},
+			None => (None, spec),
+		};
+
+		let unit = match unit {
+			"" => None,
+			unit => Some(unit.parse().map_err(|_| anyhow!("Unknown unit {} in {{:{}}}", unit, spec))?),
+		};
+
+		Ok(Spec { precision, unit })
+	}
+}
+
+
+fn format_value(value: &ScriptValue, spec: &Spec) -> Result<String> {
+	let number = match value {
+		ScriptValue::Number(number) => *number,
+		value if spec.precision.is_none() && spec.unit.is_none() => return Ok(value.to_string()),
+		value => bail!("Only numbers can be formatted with a precision or unit, not a {}", value.type_name()),
+	};
+
+	let number = match spec.unit {
+		Some(_) if number.unit == Unit::None => bail!("Cannot convert a unitless number to a unit"),
+		Some(unit) if !number.unit.same_kind(unit) => bail!("Cannot convert {} to {}", number, unit),
+		Some(unit) => number.convert_unit(unit),
+		None => number,
+	};
+
+	Ok(match spec.precision {
+		Some(precision) => format!("{:.*}{}", precision, f64::from(number), number.unit),
+		None => number.to_string(),
+	})
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::numbers::Number;
+
+	fn number(value: f64, unit: &str) -> ScriptValue {
+		ScriptValue::Number(Number::from_float_and_unit(value, unit))
+	}
+
+	fn unitless(value: f64) -> ScriptValue {
+		ScriptValue::Number(Number::from_float(value))
+	}
+
+	#[test]
+	fn placeholders() {
+		let values = [number(25.4, "mm"), ScriptValue::String("hole".to_string()), unitless(1.0 / 3.0)];
+
+		assert_eq!(format("{} {} {:.2}", &values).unwrap(), "25.4mm hole 0.33");
+		assert_eq!(format("{{{}}}", &values[1..2]).unwrap(), "{hole}");
+	}
+
+	#[test]
+	fn unit_conversion() {
+		assert_eq!(format("{:in}", &[number(25.4, "mm")]).unwrap(), "1in");
+		assert_eq!(format("{:.1cm}", &[number(1.0, "in")]).unwrap(), "2.5cm");
+		assert_eq!(format("{:.0deg}", &[number(std::f64::consts::PI, "rad")]).unwrap(), "180deg");
+	}
+
+	#[test]
+	fn errors() {
+		assert!(format("{}", &[]).is_err());
+		assert!(format("{", &[number(1.0, "mm")]).is_err());
+		assert!(format("}", &[]).is_err());
+		assert!(format("", &[number(1.0, "mm")]).is_err());
+		assert!(format("{:.xin}", &[number(1.0, "mm")]).is_err());
+		assert!(format("{:furlong}", &[number(1.0, "mm")]).is_err());
+		assert!(format("{:mm}", &[unitless(1.0)]).is_err());
+		assert!(format("{:deg}", &[number(1.0, "mm")]).is_err());
+		assert!(format("{:.2}", &[ScriptValue::String("hole".to_string())]).is_err());
+	}
+}
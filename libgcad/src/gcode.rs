@@ -1,20 +1,240 @@
-use std::{collections::HashMap, io::Write};
+use std::{
+	collections::{hash_map::DefaultHasher, HashMap},
+	hash::{Hash, Hasher},
+	io::Write,
+};
 
 use anyhow::{bail, Result};
 use nalgebra::{Matrix3, Point2};
 
-const RETRACT: f64 = 0.25;
+use crate::cancel::CancellationToken;
 
+
+const CLEARANCE: f64 = 0.25;
+/// Height above the stock top that is safe to rapid around at.
+const SAFE_Z: f64 = 5.0;
+
+/// Z coordinates are absolute work coordinates.  Operations cut from a top Z (normally the stock top) down by a positive depth,
+/// bottoming out at Z = top - depth.
 pub struct GcodeState {
 	pub stepover: f64,
 	pub depth_per_pass: f64,
 	pub feed_rate: f64,
 	pub plunge_rate: f64,
 	pub cutter_diameter: f64,
+	/// Z of the top of the stock.
+	pub stock_top: f64,
+	/// Distance above the stock top that plunges start feeding from.
+	pub clearance: f64,
 
 	pub transformation: Matrix3<f64>,
+	pub stock: Option<Stock>,
+	pub machine: MachineProfile,
+	pub postprocessor: Postprocessor,
+	pub cancellation: CancellationToken,
+
+	program: Vec<GCode>,
+	warnings: Vec<String>,
+	/// The first rapid that moved in XY while below the stock top, reported by finish.
+	missing_retract: Option<String>,
+	rpm: Option<f64>,
+	position: Position,
+	/// Code generated by earlier operations, by operation key.  None when caching is off.
+	operation_cache: Option<HashMap<u64, CachedOperation>>,
+}
 
+/// What an operation added to the program, for replaying when the same operation runs again from the same state.
+struct CachedOperation {
 	program: Vec<GCode>,
+	warnings: Vec<String>,
+	/// Kept apart from warnings, since it is only given once however often the operation repeats.
+	removal_rate_warning: Option<String>,
+}
+
+pub struct GcodeSnapshot {
+	/// Settings at the time of the snapshot, with an empty program.
+	state: GcodeState,
+	program_len: usize,
+	warnings_len: usize,
+}
+
+/// Where the program leaves the tool, in work coordinates.  Axes are None when their position is unknown.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Position {
+	pub x: Option<f64>,
+	pub y: Option<f64>,
+	pub z: Option<f64>,
+}
+
+/// Dimensions of the material being cut.  The stock's lower left corner sits at the origin.
+#[derive(Clone, Copy, Debug)]
+pub struct Stock {
+	pub width: f64,
+	pub height: f64,
+	pub thickness: Option<f64>,
+}
+
+/// A pocket floor that slopes down toward a drain point, so that liquid runs off.
+#[derive(Clone, Copy, Debug)]
+pub struct Drain {
+	pub x: f64,
+	pub y: f64,
+	/// Rise of the floor per unit of distance from the drain point.
+	pub slope: f64,
+}
+
+/// Limits of the machine the program will run on.  Limits that are None are not enforced.
+#[derive(Clone, Debug, Default)]
+pub struct MachineProfile {
+	/// Maximum feed rate for any cutting move.
+	pub max_feed: Option<f64>,
+	/// Maximum feed rate of the Z axis alone.  Moves with a Z component are slowed down so that Z stays under this limit.
+	pub max_z_feed: Option<f64>,
+	/// Feed rate the machine moves at during rapids.
+	pub rapid_feed: Option<f64>,
+	/// Size of the work area.
+	pub max_x: Option<f64>,
+	pub max_y: Option<f64>,
+	/// Highest material removal rate the spindle can keep up with, in mm^3/min.  Operations that go over it are warned about.
+	pub max_mrr: Option<f64>,
+	pub axes: AxisMap,
+}
+
+/// Where each axis of the program is written in the output, for machines with swapped or reversed axes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisMap {
+	/// For the output X, Y and Z: the program axis (0 for X, 1 for Y, 2 for Z) it comes from, and -1.0 if reversed.
+	axes: [(usize, f64); 3],
+}
+
+impl Default for AxisMap {
+	fn default() -> Self {
+		AxisMap {
+			axes: [(0, 1.0), (1, 1.0), (2, 1.0)],
+		}
+	}
+}
+
+impl AxisMap {
+	/// Parses a list of the program axis each output axis comes from, e.g. 'Y,X,Z' swaps X and Y and 'X,-Y,Z' reverses Y.
+	pub fn parse(spec: &str) -> Result<AxisMap> {
+		let mut axes = Vec::new();
+
+		for axis in spec.split(',').map(str::trim) {
+			let (sign, name) = match axis.strip_prefix('-') {
+				Some(name) => (-1.0, name),
+				None => (1.0, axis.strip_prefix('+').unwrap_or(axis)),
+			};
+			let index = match name {
+				"X" | "x" => 0,
+				"Y" | "y" => 1,
+				"Z" | "z" => 2,
+				_ => bail!("Unknown axis {} in {}; expected something like 'X,-Y,Z'", axis, spec),
+			};
+
+			if axes.iter().any(|&(used, _)| used == index) {
+				bail!("Axis {} is used twice in {}", name.to_ascii_uppercase(), spec);
+			}
+			axes.push((index, sign));
+		}
+
+		match axes.try_into() {
+			Ok(axes) => Ok(AxisMap { axes }),
+			Err(_) => bail!("Expected three axes in {}, e.g. 'X,-Y,Z'", spec),
+		}
+	}
+
+	fn map(&self, point: [Option<f64>; 3]) -> [Option<f64>; 3] {
+		self.axes.map(|(axis, sign)| point[axis].map(|value| value * sign))
+	}
+
+	/// Returns a copy of line with its coordinates moved to the output axes.
+	fn apply(&self, line: &GCode) -> Result<GCode> {
+		if *self == AxisMap::default() {
+			return Ok(line.clone());
+		}
+
+		Ok(match line {
+			GCode::RapidMove { x, y, z } => {
+				let [x, y, z] = self.map([*x, *y, *z]);
+				GCode::RapidMove { x, y, z }
+			},
+			GCode::LinearMove { x, y, z, feed } => {
+				let [x, y, z] = self.map([*x, *y, *z]);
+				GCode::LinearMove { x, y, z, feed: *feed }
+			},
+			GCode::ClockwiseArc { x, y, cx, cy, feed } | GCode::CounterClockwiseArc { x, y, cx, cy, feed } => {
+				if self.axes[2].0 != 2 {
+					bail!("Arcs can't be written when Z is swapped with X or Y");
+				}
+
+				let [x, y, _] = self.map([Some(*x), Some(*y), None]);
+				let [cx, cy, _] = self.map([Some(*cx), Some(*cy), None]);
+				let (x, y, cx, cy, feed) = (x.unwrap(), y.unwrap(), cx.unwrap(), cy.unwrap(), *feed);
+
+				// Swapping or reversing one of X and Y mirrors the arc, which reverses its direction
+				let swapped = self.axes[0].0 == 1;
+				let mirrored = (self.axes[0].1 * self.axes[1].1 < 0.0) != swapped;
+
+				match (line, mirrored) {
+					(GCode::ClockwiseArc { .. }, false) | (GCode::CounterClockwiseArc { .. }, true) => GCode::ClockwiseArc { x, y, cx, cy, feed },
+					_ => GCode::CounterClockwiseArc { x, y, cx, cy, feed },
+				}
+			},
+			GCode::MoveInAbsoluteCoordinates(line) => GCode::MoveInAbsoluteCoordinates(Box::new(self.apply(line)?)),
+			_ => line.clone(),
+		})
+	}
+}
+
+/// Options controlling how the program is written out for a particular controller.
+#[derive(Clone, Debug)]
+pub struct Postprocessor {
+	/// Emit rapids as G1 moves at the machine's rapid feed, for controllers that don't treat G0 safely.
+	pub rapids_as_feed: bool,
+	pub flavor: Flavor,
+	/// Turn the part cooling fan on and off with the spindle, for boards where the fan output drives a vacuum.
+	pub fan_as_vacuum: bool,
+	/// Write G and M codes with two digits (G01 instead of G1).
+	pub padded_codes: bool,
+	/// Leave out the spaces between words.
+	pub no_spaces: bool,
+	/// Always write a decimal point in numbers (X10. instead of X10), for controllers that read integers as thousandths.
+	pub decimal_point: bool,
+	/// Write a comment summarizing each operation before it and another after it, for senders that outline the program.
+	pub operation_comments: bool,
+	/// Pause before each operation, for stepping through a program while proving it.
+	pub pause_operations: bool,
+	/// Factors every feed rate and spindle speed are multiplied by, for proving a program at reduced speed.  Rapids are not
+	/// scaled.
+	pub feed_scale: f64,
+	pub rpm_scale: f64,
+}
+
+impl Default for Postprocessor {
+	fn default() -> Self {
+		Postprocessor {
+			rapids_as_feed: false,
+			flavor: Flavor::default(),
+			fan_as_vacuum: false,
+			padded_codes: false,
+			no_spaces: false,
+			decimal_point: false,
+			operation_comments: false,
+			pause_operations: false,
+			feed_scale: 1.0,
+			rpm_scale: 1.0,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Flavor {
+	/// LinuxCNC style output.
+	#[default]
+	Standard,
+	/// Marlin/RepRap firmware on CNC-converted 3D printer boards.
+	Marlin,
 }
 
 impl GcodeState {
@@ -25,38 +245,334 @@ impl GcodeState {
 			feed_rate: 0.0,
 			plunge_rate: 0.0,
 			cutter_diameter: 0.0,
+			stock_top: 0.0,
+			clearance: CLEARANCE,
 
 			transformation: Matrix3::identity(),
+			stock: None,
+			machine: MachineProfile::default(),
+			postprocessor: Postprocessor::default(),
+			cancellation: CancellationToken::new(),
 
 			program: Vec::new(),
+			warnings: Vec::new(),
+			missing_retract: None,
+			rpm: None,
+			position: Position::default(),
+			operation_cache: None,
+		}
+	}
+
+	/// Starts a new program, keeping the machine and postprocessor settings and the current material and cutter.
+	pub fn reset(&mut self) {
+		*self = GcodeState {
+			stepover: self.stepover,
+			depth_per_pass: self.depth_per_pass,
+			feed_rate: self.feed_rate,
+			plunge_rate: self.plunge_rate,
+			cutter_diameter: self.cutter_diameter,
+			machine: std::mem::take(&mut self.machine),
+			postprocessor: std::mem::take(&mut self.postprocessor),
+			cancellation: self.cancellation.clone(),
+			operation_cache: self.operation_cache.take(),
+			..GcodeState::new()
+		};
+	}
+
+	/// Captures the settings and the current length of the program, which only grows until finish, for rolling back to.
+	pub fn snapshot(&self) -> GcodeSnapshot {
+		GcodeSnapshot {
+			state: GcodeState {
+				stepover: self.stepover,
+				depth_per_pass: self.depth_per_pass,
+				feed_rate: self.feed_rate,
+				plunge_rate: self.plunge_rate,
+				cutter_diameter: self.cutter_diameter,
+				stock_top: self.stock_top,
+				clearance: self.clearance,
+
+				transformation: self.transformation,
+				stock: self.stock,
+				machine: self.machine.clone(),
+				postprocessor: self.postprocessor.clone(),
+				cancellation: self.cancellation.clone(),
+
+				program: Vec::new(),
+				warnings: Vec::new(),
+				missing_retract: self.missing_retract.clone(),
+				rpm: self.rpm,
+				position: self.position,
+				operation_cache: None,
+			},
+			program_len: self.program.len(),
+			warnings_len: self.warnings.len(),
+		}
+	}
+
+	/// Rolls back to a snapshot, dropping the code and warnings generated since.  The cancellation token is kept.
+	pub fn restore(&mut self, snapshot: &GcodeSnapshot) {
+		let mut program = std::mem::take(&mut self.program);
+		let mut warnings = std::mem::take(&mut self.warnings);
+		program.truncate(snapshot.program_len);
+		warnings.truncate(snapshot.warnings_len);
+
+		*self = GcodeState {
+			machine: snapshot.state.machine.clone(),
+			postprocessor: snapshot.state.postprocessor.clone(),
+			cancellation: self.cancellation.clone(),
+			program,
+			warnings,
+			missing_retract: snapshot.state.missing_retract.clone(),
+			operation_cache: self.operation_cache.take(),
+			..snapshot.state
+		};
+	}
+
+	/// Turns caching of generated operations on or off.  Turning it off drops anything cached.
+	pub fn set_operation_cache(&mut self, enabled: bool) {
+		self.operation_cache = enabled.then(|| self.operation_cache.take().unwrap_or_default());
+	}
+
+	/// Generates one toolpath operation, marking where it starts and ends in the program.  With caching on, an operation that
+	/// already ran with the same inputs from the same state is replayed instead.  inputs must cover everything the operation
+	/// depends on besides this state.
+	pub fn operation(&mut self, name: &str, inputs: &[f64], generate: impl FnOnce(&mut GcodeState) -> Result<()>) -> Result<()> {
+		let key = self.operation_cache.is_some().then(|| self.operation_key(name, inputs));
+		if let Some(cached) = key.and_then(|key| self.operation_cache.as_ref()?.get(&key)) {
+			let (program, warnings, removal_rate_warning) = (cached.program.clone(), cached.warnings.clone(), cached.removal_rate_warning.clone());
+
+			for code in program {
+				self.push(code);
+			}
+			self.warnings.extend(warnings);
+			self.warn_once(removal_rate_warning);
+
+			return Ok(());
+		}
+
+		let (program_len, warnings_len) = (self.program.len(), self.warnings.len());
+		let start = self.position;
+
+		// The start marker is filled in once the extent of the operation is known
+		self.program.push(GCode::OperationStart(Box::default()));
+		generate(self)?;
+		let operation = self.describe_operation(name, program_len + 1, start);
+		let removal_rate_warning = self.removal_rate_warning(&operation);
+		self.program[program_len] = GCode::OperationStart(Box::new(operation));
+		self.program.push(GCode::OperationEnd(name.to_string()));
+
+		if let (Some(key), Some(cache)) = (key, &mut self.operation_cache) {
+			let cached = CachedOperation {
+				program: self.program[program_len..].to_vec(),
+				warnings: self.warnings[warnings_len..].to_vec(),
+				removal_rate_warning: removal_rate_warning.clone(),
+			};
+			cache.insert(key, cached);
+		}
+
+		self.warn_once(removal_rate_warning);
+
+		Ok(())
+	}
+
+	/// Summarizes the code from index from onward, which starts with the tool at start.
+	fn describe_operation(&self, name: &str, from: usize, start: Position) -> Operation {
+		let mut position = start;
+		let mut bottom: Option<f64> = None;
+		let mut bounds: Option<[f64; 4]> = None;
+
+		for code in &self.program[from..] {
+			let extent = match *code {
+				GCode::RapidMove { x, y, .. } | GCode::LinearMove { x, y, .. } if x.is_some() || y.is_some() => {
+					x.or(position.x).zip(y.or(position.y)).map(|point| (point, 0.0))
+				},
+				// Arcs count as their whole circle, which is never smaller than the arc
+				GCode::ClockwiseArc { x, y, cx, cy, .. } | GCode::CounterClockwiseArc { x, y, cx, cy, .. } => Some(((cx, cy), (x - cx).hypot(y - cy))),
+				_ => None,
+			};
+
+			if let Some(((x, y), radius)) = extent {
+				let [min_x, min_y, max_x, max_y] = bounds.get_or_insert([f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY]);
+				*min_x = min_x.min(x - radius);
+				*min_y = min_y.min(y - radius);
+				*max_x = max_x.max(x + radius);
+				*max_y = max_y.max(y + radius);
+			}
+
+			if let GCode::LinearMove { z: Some(z), .. } = *code {
+				bottom = Some(bottom.map_or(z, |bottom| bottom.min(z)));
+			}
+
+			position.update(code);
+		}
+
+		Operation {
+			name: name.to_string(),
+			cutter_diameter: self.cutter_diameter,
+			bottom,
+			bounds,
+		}
+	}
+
+	/// A warning if the operation's heaviest cut removes material faster than the machine's max_mrr.  The heaviest cut is a
+	/// full-width slot at one pass's depth, since clearing starts with one whatever the stepover, or a plunge of the cutter.
+	fn removal_rate_warning(&self, operation: &Operation) -> Option<String> {
+		let (Some(max_mrr), Some(bottom)) = (self.machine.max_mrr, operation.bottom) else {
+			return None;
+		};
+
+		let depth_of_cut = self.depth_per_pass.min(self.stock_top - bottom);
+		let slot = self.cutter_diameter * depth_of_cut * self.feed_rate;
+		let plunge = std::f64::consts::PI * (self.cutter_diameter / 2.0).powi(2) * self.plunge_rate;
+		let (mrr, advice) = match slot > plunge {
+			true => (slot, "lower the feed rate or depth per pass"),
+			false => (plunge, "lower the plunge rate"),
+		};
+		if mrr <= max_mrr {
+			return None;
+		}
+
+		Some(format!(
+			"{} removes up to {} mm^3/min, more than the machine's max_mrr of {}; {}",
+			operation.name,
+			format_number(mrr.round()),
+			format_number(max_mrr),
+			advice
+		))
+	}
+
+	/// Adds a warning unless it was already given, for warnings that repeats of the same operation would give over and over.
+	fn warn_once(&mut self, warning: Option<String>) {
+		if let Some(warning) = warning.filter(|warning| !self.warnings.contains(warning)) {
+			self.warnings.push(warning);
+		}
+	}
+
+	/// Hash of an operation's inputs and every setting that affects the code it generates.
+	fn operation_key(&self, operation: &str, inputs: &[f64]) -> u64 {
+		let stock = self.stock.map_or([None; 3], |stock| [Some(stock.width), Some(stock.height), stock.thickness]);
+		let machine = &self.machine;
+		let settings = [
+			Some(self.stepover),
+			Some(self.depth_per_pass),
+			Some(self.feed_rate),
+			Some(self.plunge_rate),
+			Some(self.cutter_diameter),
+			Some(self.stock_top),
+			Some(self.clearance),
+			machine.max_feed,
+			machine.max_z_feed,
+			machine.rapid_feed,
+			machine.max_x,
+			machine.max_y,
+			machine.max_mrr,
+			self.rpm,
+			self.position.x,
+			self.position.y,
+			self.position.z,
+		];
+
+		let mut hasher = DefaultHasher::new();
+		operation.hash(&mut hasher);
+		inputs.len().hash(&mut hasher);
+		for value in inputs
+			.iter()
+			.copied()
+			.map(Some)
+			.chain(settings)
+			.chain(stock)
+			.chain(self.transformation.iter().copied().map(Some))
+		{
+			value.map(f64::to_bits).hash(&mut hasher);
+		}
+
+		hasher.finish()
+	}
+
+	pub fn warnings(&self) -> &[String] {
+		&self.warnings
+	}
+
+	pub fn warn(&mut self, warning: String) {
+		self.warnings.push(warning);
+	}
+
+	/// Appends code to the program, keeping track of where it leaves the tool.
+	fn push(&mut self, code: GCode) {
+		if self.missing_retract.is_none() {
+			self.missing_retract = self.check_retract(&code);
 		}
+
+		self.position.update(&code);
+		self.program.push(code);
+	}
+
+	/// Checks for a rapid that moves in XY while the tool is below the stock top, which rapid_move() prevents but code pushed
+	/// any other way might not.  The stock top is the one in effect when the rapid is emitted.
+	fn check_retract(&self, code: &GCode) -> Option<String> {
+		let GCode::RapidMove { x, y, z } = *code else {
+			return None;
+		};
+		let position = self.position;
+		let moves_xy = x.is_some_and(|x| position.x != Some(x)) || y.is_some_and(|y| position.y != Some(y));
+		let low_z = [position.z, z].into_iter().flatten().find(|&z| z < self.stock_top)?;
+
+		moves_xy.then(|| {
+			format!(
+				"Rapid move to X{} Y{} at Z{}, below the stock top; a retract is missing",
+				format_number(x.or(position.x).unwrap_or(0.0)),
+				format_number(y.or(position.y).unwrap_or(0.0)),
+				format_number(low_z)
+			)
+		})
 	}
 
 	pub fn write_header(&mut self) {
-		self.program.push(GCode::AbsoluteDistanceMode);
-		self.program.push(GCode::MetricUnits);
-		self.program.push(GCode::Comment("Move to safe Z".to_string()));
-		self.program.push(GCode::MoveInAbsoluteCoordinates(Box::new(GCode::RapidMove {
+		self.push(GCode::AbsoluteDistanceMode);
+		self.push(GCode::MetricUnits);
+		self.push(GCode::Comment("Move to safe Z".to_string()));
+		self.push(GCode::MoveInAbsoluteCoordinates(Box::new(GCode::RapidMove {
 			x: None,
 			y: None,
 			z: Some(-5.0),
 		})));
-		self.program.push(GCode::SpindleStop);
+		self.push(GCode::SpindleStop);
 	}
 
 	pub fn set_rpm(&mut self, rpm: f64) {
-		self.program.push(GCode::SpindleOnCW { rpm });
+		self.push(GCode::SpindleOnCW { rpm });
+		self.rpm = Some(rpm);
+	}
+
+	/// Retracts, stops the spindle, and pauses the program until the operator resumes it.
+	/// If flip_axis is given, the operator is expected to flip the stock over that axis and all following operations are mirrored to match.
+	pub fn pause(&mut self, message: &str, flip_axis: Option<char>) -> Result<()> {
+		// Check the flip before anything is emitted
+		if let Some(axis) = flip_axis {
+			self.mirror_stock(axis)?;
+		}
+
+		self.retract();
+		self.push(GCode::SpindleStop);
+		self.push(GCode::Comment(message.to_string()));
+		self.push(GCode::ProgramPause);
+
+		if let Some(rpm) = self.rpm {
+			self.push(GCode::SpindleOnCW { rpm });
+		}
+
+		Ok(())
 	}
 
 	pub fn write_comment(&mut self, comment: &str) {
-		self.program.push(GCode::Comment(comment.to_string()));
+		self.push(GCode::Comment(comment.to_string()));
 	}
 
 	pub fn cutting_move(&mut self, x: f64, y: f64, z: Option<f64>) {
 		let xy = Point2::new(x, y);
 		let xy = self.transformation.transform_point(&xy);
 
-		self.program.push(GCode::LinearMove {
+		self.push(GCode::LinearMove {
 			x: Some(xy.x),
 			y: Some(xy.y),
 			z,
@@ -65,7 +581,7 @@ impl GcodeState {
 	}
 
 	pub fn plunge(&mut self, z: f64) {
-		self.program.push(GCode::LinearMove {
+		self.push(GCode::LinearMove {
 			x: None,
 			y: None,
 			z: Some(z),
@@ -73,60 +589,218 @@ impl GcodeState {
 		});
 	}
 
-	pub fn rapid_move(&mut self, x: f64, y: f64, z: Option<f64>) {
+	pub fn rapid_move(&mut self, x: f64, y: f64, z: Option<f64>) -> Result<()> {
 		let xy = Point2::new(x, y);
 		let xy = self.transformation.transform_point(&xy);
+		let moves_xy = self.position.x != Some(xy.x) || self.position.y != Some(xy.y);
+		let below_stock = |z: Option<f64>| z.is_some_and(|z| z < self.stock_top);
+
+		if moves_xy && (below_stock(self.position.z) || below_stock(z)) {
+			bail!("Rapid move to X{} Y{} while below the stock top", format_number(xy.x), format_number(xy.y));
+		}
 
-		self.program.push(GCode::RapidMove {
+		self.push(GCode::RapidMove {
 			x: Some(xy.x),
 			y: Some(xy.y),
 			z,
 		});
+
+		Ok(())
 	}
 
-	pub fn rapid_move_xy(&mut self, x: f64, y: f64) {
+	pub fn rapid_move_xy(&mut self, x: f64, y: f64) -> Result<()> {
 		self.rapid_move(x, y, None)
 	}
 
+	/// Rapids up to safe Z, unless the tool is known to already be there.
+	pub fn retract(&mut self) {
+		let safe_z = self.stock_top + SAFE_Z;
+
+		if self.position.z.is_none_or(|z| z < safe_z) {
+			self.push(GCode::RapidMove {
+				x: None,
+				y: None,
+				z: Some(safe_z),
+			});
+		}
+	}
+
+	/// Rapids down to the clearance height above top, so that a following plunge doesn't feed through air.
+	pub fn approach(&mut self, top: f64) {
+		let clearance_z = top + self.clearance;
+
+		if self.position.z.is_none_or(|z| z > clearance_z) {
+			self.push(GCode::RapidMove {
+				x: None,
+				y: None,
+				z: Some(clearance_z),
+			});
+		}
+	}
+
 	pub fn arc_cut(&mut self, x: f64, y: f64, cx: f64, cy: f64) {
 		let xy = self.transformation.transform_point(&Point2::new(x, y));
 		let cxy = self.transformation.transform_point(&Point2::new(cx, cy));
 
-		self.program.push(GCode::CounterClockwiseArc {
-			x: xy.x,
-			y: xy.y,
-			cx: cxy.x,
-			cy: cxy.y,
-			feed: self.feed_rate,
-		});
+		// A mirroring transformation reverses the direction of the arc
+		if self.transformation.determinant() < 0.0 {
+			self.push(GCode::ClockwiseArc {
+				x: xy.x,
+				y: xy.y,
+				cx: cxy.x,
+				cy: cxy.y,
+				feed: self.feed_rate,
+			});
+		} else {
+			self.push(GCode::CounterClockwiseArc {
+				x: xy.x,
+				y: xy.y,
+				cx: cxy.x,
+				cy: cxy.y,
+				feed: self.feed_rate,
+			});
+		}
 	}
 
-	pub fn drill(&mut self, x: f64, y: f64, depth: f64) {
-		self.rapid_move_xy(x, y);
-		self.rapid_move(x, y, Some(0.25));
-		self.plunge(-depth);
-		self.rapid_move(x, y, Some(5.0));
+	/// Mirrors all following operations as if the stock had been flipped over the given axis.
+	fn mirror_stock(&mut self, axis: char) -> Result<()> {
+		let stock = match self.stock {
+			Some(stock) => stock,
+			None => bail!("Flipping requires the stock to be defined"),
+		};
+
+		// Flipping over X mirrors Y across the stock's centerline, and vice versa
+		let flip = match axis {
+			'x' => Matrix3::new(1.0, 0.0, 0.0, 0.0, -1.0, stock.height, 0.0, 0.0, 1.0),
+			'y' => Matrix3::new(-1.0, 0.0, stock.width, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0),
+			_ => bail!("Unknown flip axis: {}", axis),
+		};
+
+		self.transformation = flip * self.transformation;
+
+		Ok(())
+	}
+
+	/// Drills a pair of registration pin holes through the stock on the flip axis' centerline, pauses so the stock can be flipped
+	/// onto pins set in those holes, then mirrors all following operations to match.  pin_depth should reach into the spoilboard.
+	pub fn flip(&mut self, axis: char, pin_depth: f64, pin_inset: f64) -> Result<()> {
+		let stock = match self.stock {
+			Some(stock) => stock,
+			None => bail!("Flipping requires the stock to be defined"),
+		};
+
+		// Holes on the centerline land on themselves after flipping
+		let pins = match axis {
+			'x' => [(pin_inset, stock.height / 2.0), (stock.width - pin_inset, stock.height / 2.0)],
+			'y' => [(stock.width / 2.0, pin_inset), (stock.width / 2.0, stock.height - pin_inset)],
+			_ => bail!("Unknown flip axis: {}", axis),
+		};
+
+		// Pins are positioned relative to the stock, not the current transformation
+		let transformation = self.transformation;
+		self.transformation = Matrix3::identity();
+
+		self.write_comment("Registration pin holes");
+		for (x, y) in pins {
+			self.drill(x, y, self.stock_top, pin_depth)?;
+		}
+
+		let message = format!("Flip the stock over {} onto the registration pins", axis.to_ascii_uppercase());
+		self.transformation = transformation;
+		self.pause(&message, Some(axis))
+	}
+
+	pub fn drill(&mut self, x: f64, y: f64, top: f64, depth: f64) -> Result<()> {
+		check_depth(depth)?;
+
+		self.retract();
+		self.rapid_move_xy(x, y)?;
+		self.approach(top);
+		self.plunge(top - depth);
+		self.retract();
+
+		Ok(())
+	}
+
+	/// Drills each (x, y, depth) hole in order, hopping between them just above the surface instead of retracting to safe Z.
+	pub fn drill_holes(&mut self, holes: &[(f64, f64, f64)], top: f64) -> Result<()> {
+		for &(_, _, depth) in holes {
+			check_depth(depth)?;
+		}
+
+		let hop_z = top.max(self.stock_top) + self.clearance;
+
+		self.retract();
+
+		for &(x, y, depth) in holes {
+			self.cancellation.check()?;
+			self.rapid_move_xy(x, y)?;
+			self.approach(top);
+			self.plunge(top - depth);
+			self.push(GCode::RapidMove {
+				x: None,
+				y: None,
+				z: Some(hop_z),
+			});
+		}
+
+		self.retract();
+
+		Ok(())
 	}
 
-	pub fn contour_line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64, depth: f64) -> Result<()> {
+	/// Cuts along path, with the depth changing linearly with the distance along the path from start_depth to end_depth.
+	pub fn contour_path(&mut self, path: &[(f64, f64)], top: f64, start_depth: f64, end_depth: f64) -> Result<()> {
+		if start_depth < 0.0 || end_depth < 0.0 {
+			bail!("Depth must not be negative");
+		}
+
+		check_depth(start_depth.max(end_depth))?;
+
 		if self.depth_per_pass <= 0.0 {
 			bail!("Invalid depth per pass");
 		}
 
-		let n_passes = (depth / self.depth_per_pass).ceil() as i64;
+		if path.len() < 2 {
+			bail!("A path needs at least two points");
+		}
+
+		// Distance along the path to each point
+		let mut distances = vec![0.0];
+		for (a, b) in path.iter().zip(path.iter().skip(1)) {
+			distances.push(distances.last().unwrap() + (b.0 - a.0).hypot(b.1 - a.1));
+		}
+		let length = *distances.last().unwrap();
+
+		let n_passes = (start_depth.max(end_depth) / self.depth_per_pass).ceil() as i64;
 
 		for layer in 1..=n_passes {
-			let z = -(depth * layer as f64 / n_passes as f64);
-			self.rapid_move_xy(x1, y1);
-			self.plunge(z);
-			self.cutting_move(x2, y2, None);
-			self.rapid_move(x2, y2, Some(5.0));
+			self.cancellation.check()?;
+			let fraction = layer as f64 / n_passes as f64;
+			let z_at = |distance: f64| {
+				let t = if length > 0.0 { distance / length } else { 0.0 };
+				top - (start_depth + (end_depth - start_depth) * t) * fraction
+			};
+			let (x, y) = path[0];
+
+			self.retract();
+			self.rapid_move_xy(x, y)?;
+			self.approach(top);
+			self.plunge(z_at(0.0));
+
+			for (&(x, y), &distance) in path.iter().zip(distances.iter()).skip(1) {
+				self.cutting_move(x, y, Some(z_at(distance)));
+			}
+
+			self.retract();
 		}
 
 		Ok(())
 	}
 
-	pub fn circle_pocket(&mut self, cx: f64, cy: f64, diameter: f64, depth: f64) -> Result<()> {
+	pub fn circle_pocket(&mut self, cx: f64, cy: f64, diameter: f64, top: f64, depth: f64) -> Result<()> {
+		check_depth(depth)?;
+
 		if diameter <= self.cutter_diameter {
 			bail!("Diameter must be greater than cutter diameter");
 		}
@@ -143,11 +817,13 @@ impl GcodeState {
 		let n_passes = (depth / self.depth_per_pass).ceil() as i64;
 		let x_offset = (diameter / 2.0) - (self.cutter_diameter * n_circles as f64 / 2.0);
 
-		self.rapid_move_xy(cx + x_offset, cy);
-		self.plunge(2.5);
+		self.retract();
+		self.rapid_move_xy(cx + x_offset, cy)?;
+		self.approach(top);
 
 		for i in 1..=n_passes {
-			self.plunge(-(depth * i as f64 / n_passes as f64));
+			self.cancellation.check()?;
+			self.plunge(top - depth * i as f64 / n_passes as f64);
 
 			for j in 1..=n_circles {
 				self.arc_cut(cx - x_offset - self.cutter_diameter * (j - 1) as f64 / 2.0, cy, cx, cy);
@@ -164,25 +840,42 @@ impl GcodeState {
 			}
 		}
 
-		self.rapid_move(cx + x_offset + self.cutter_diameter * (n_circles - 1) as f64 / 2.0, cy, Some(5.0));
+		self.retract();
 
 		Ok(())
 	}
 
 	pub fn finish<W: Write>(&mut self, writer: W) -> Result<()> {
+		if let Some(error) = self.missing_retract.take() {
+			bail!(error);
+		}
+
 		self.program.push(GCode::ProgramEnd);
 		self.write_program(writer)
 	}
 
-	fn write_program<W: Write>(&self, mut writer: W) -> Result<()> {
+	fn write_program<W: Write>(&mut self, mut writer: W) -> Result<()> {
 		let mut last_command = None;
 		let mut state = HashMap::new();
+		let mut clamped_feeds = Vec::new();
+
+		for line in self.program.iter().flat_map(|line| self.postprocessor.rewrite(line, self.stock_top)) {
+			let line = &line;
 
-		for line in &self.program {
 			if let GCode::Comment(comment) = &line {
-				writer.write_all(format!("({})\n", comment).as_bytes())?;
+				for comment in comment.split('\n').map(|comment| sanitize_comment(comment, self.postprocessor.flavor)) {
+					match self.postprocessor.flavor {
+						Flavor::Standard => writer.write_all(format!("({})\n", comment).as_bytes())?,
+						Flavor::Marlin => writer.write_all(format!("; {}\n", comment).as_bytes())?,
+					}
+				}
 				continue;
 			}
+			let line = &self.machine.axes.apply(line)?;
+			let line = &self.postprocessor.scale_speeds(line);
+			// Rapids turned into feed moves are clamped like any other
+			let line = &self.rapid_as_feed(line)?;
+			let line = &self.machine.clamp_feed(line, &state, &mut clamped_feeds);
 			let words = line.to_words(state.get(&'X').cloned(), state.get(&'Y').cloned())?;
 			let mut pieces = Vec::new();
 			let mut g53 = false;
@@ -204,6 +897,8 @@ impl GcodeState {
 							pieces.push(*word);
 						}
 					},
+					// Marlin runs the spindle at full power on an M3 without S, so the power always goes with it
+					GcodeWord::S(_) if self.postprocessor.flavor == Flavor::Marlin => pieces.push(*word),
 					GcodeWord::X(v) | GcodeWord::Y(v) | GcodeWord::Z(v) | GcodeWord::I(v) | GcodeWord::J(v) | GcodeWord::F(v) | GcodeWord::S(v) => {
 						if g53 || state.get(&word.to_char()) != Some(v) {
 							pieces.push(*word);
@@ -217,7 +912,9 @@ impl GcodeState {
 				continue;
 			}
 
-			writer.write_all(pieces.iter().map(|w| w.to_string()).collect::<Vec<String>>().join(" ").as_bytes())?;
+			let separator = if self.postprocessor.no_spaces { "" } else { " " };
+			let pieces_str = pieces.iter().map(|w| self.postprocessor.format_word(*w)).collect::<Vec<String>>();
+			writer.write_all(pieces_str.join(separator).as_bytes())?;
 			writer.write_all(b"\n")?;
 
 			// Update state based on the command as written
@@ -240,12 +937,50 @@ impl GcodeState {
 			}
 		}
 
+		for (requested, lowest, highest) in clamped_feeds {
+			let clamped = if lowest == highest {
+				format_number(lowest)
+			} else {
+				format!("between {} and {}", format_number(lowest), format_number(highest))
+			};
+
+			self.warnings.push(format!(
+				"Feed rate {} exceeds the machine limits, clamped to {}",
+				format_number(requested),
+				clamped
+			));
+		}
+
 		Ok(())
 	}
 
+	/// Rewrites rapids as G1 moves at the machine's rapid feed if the postprocessor asks for it.
+	fn rapid_as_feed(&self, line: &GCode) -> Result<GCode> {
+		if !self.postprocessor.rapids_as_feed {
+			return Ok(line.clone());
+		}
+
+		Ok(match line {
+			GCode::RapidMove { x, y, z } => {
+				let feed = match self.machine.rapid_feed {
+					Some(feed) => feed,
+					None => bail!("Emitting rapids as feed moves requires the machine's rapid_feed"),
+				};
+
+				GCode::LinearMove { x: *x, y: *y, z: *z, feed }
+			},
+			GCode::MoveInAbsoluteCoordinates(gcode) => GCode::MoveInAbsoluteCoordinates(Box::new(self.rapid_as_feed(gcode)?)),
+			_ => line.clone(),
+		})
+	}
+
 	/// Cuts a rectangular pocket with the given dimensions, and x y specifying the lower left corner.
 	/// Note that this only handles narrow rectangles right now, hence the name groove.
-	pub fn groove_pocket(&mut self, x: f64, y: f64, width: f64, height: f64, depth: f64) -> Result<()> {
+	/// With a drain, depth is the depth at the drain point and the floor rises away from it.
+	#[allow(clippy::too_many_arguments)]
+	pub fn groove_pocket(&mut self, x: f64, y: f64, width: f64, height: f64, top: f64, depth: f64, drain: Option<Drain>) -> Result<()> {
+		check_depth(depth)?;
+
 		if self.stepover <= 0.0 {
 			bail!("Invalid stepover: {}", self.stepover);
 		}
@@ -262,7 +997,7 @@ impl GcodeState {
 		let mut c_width = width - self.cutter_diameter;
 		let mut c_height = height - self.cutter_diameter;
 		let n_passes = (depth / self.depth_per_pass).ceil() as i64;
-		let n_loops = 1 + (((width / 2.0) - self.cutter_diameter) / self.stepover).ceil() as i64;
+		let n_loops = (1 + (((width / 2.0) - self.cutter_diameter) / self.stepover).ceil() as i64).max(1);
 
 		for _ in 0..n_loops {
 			pattern.push((c_x, c_y));
@@ -282,27 +1017,55 @@ impl GcodeState {
 
 		pattern.reverse();
 
+		// A sloped floor changes height along each side of the pattern, so break the sides up
+		if drain.is_some() {
+			let mut points = vec![pattern[0]];
+
+			for (a, b) in pattern.iter().zip(pattern.iter().skip(1)) {
+				let steps = ((b.0 - a.0).hypot(b.1 - a.1) / self.stepover).ceil().max(1.0) as usize;
+
+				for step in 1..=steps {
+					let t = step as f64 / steps as f64;
+					points.push((a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t));
+				}
+			}
+
+			pattern = points;
+		}
+
+		let floor_z = |(x, y): (f64, f64)| match drain {
+			Some(drain) => (top - depth + drain.slope * (x - drain.x).hypot(y - drain.y)).min(top),
+			None => f64::MIN,
+		};
+
 		for layer in 1..=n_passes {
-			let z = -(depth * layer as f64 / n_passes as f64);
+			self.cancellation.check()?;
+			let z = top - depth * layer as f64 / n_passes as f64;
+			let z_at = |point: (f64, f64)| z.max(floor_z(point));
 			let (x, y) = pattern[0];
 
 			if layer == 1 {
-				self.rapid_move_xy(x, y);
-				self.rapid_move(x, y, Some(5.0));
-				self.plunge(z);
-			} else {
-				self.rapid_move_xy(x, y);
-				self.plunge(z);
+				self.retract();
+				self.rapid_move_xy(x, y)?;
+				self.approach(top);
 			}
 
-			for (x, y) in pattern.iter().skip(1) {
-				self.cutting_move(*x, *y, None);
+			self.plunge(z_at(pattern[0]));
+
+			for &point in pattern.iter().skip(1) {
+				self.cutting_move(point.0, point.1, Some(z_at(point)));
 			}
 
 			if layer == n_passes {
-				self.rapid_move(x, y, Some(5.0));
+				self.retract();
+			} else if drain.is_some() {
+				// The floor rises away from the drain, so climb above its highest point before heading back
+				let highest = pattern.iter().map(|&point| z_at(point)).fold(f64::MIN, f64::max);
+				self.plunge(highest + self.clearance);
+				self.cutting_move(x, y, None);
 			} else {
-				self.rapid_move(x, y, Some(z + RETRACT));
+				// Head back to the start just above the floor that was cut; this stays inside the pocket
+				self.cutting_move(x, y, Some(z + self.clearance));
 			}
 		}
 
@@ -311,6 +1074,200 @@ impl GcodeState {
 }
 
 
+impl Position {
+	fn update(&mut self, code: &GCode) {
+		match code {
+			GCode::RapidMove { x, y, z } | GCode::LinearMove { x, y, z, .. } => {
+				self.x = x.or(self.x);
+				self.y = y.or(self.y);
+				self.z = z.or(self.z);
+			},
+			GCode::ClockwiseArc { x, y, .. } | GCode::CounterClockwiseArc { x, y, .. } => {
+				self.x = Some(*x);
+				self.y = Some(*y);
+			},
+			// Machine coordinates can't be related to work coordinates, so any axis moved this way is lost
+			GCode::MoveInAbsoluteCoordinates(code) => {
+				let mut moved = Position::default();
+				moved.update(code);
+
+				if moved.x.is_some() {
+					self.x = None;
+				}
+
+				if moved.y.is_some() {
+					self.y = None;
+				}
+
+				if moved.z.is_some() {
+					self.z = None;
+				}
+			},
+			_ => {},
+		}
+	}
+}
+
+
+impl Postprocessor {
+	/// Replaces line with the equivalent commands for the target controller.
+	fn rewrite(&self, line: &GCode, stock_top: f64) -> Vec<GCode> {
+		match (self.flavor, line) {
+			// Marlin has no machine coordinate moves, so the safe Z move happens in work coordinates
+			(Flavor::Marlin, GCode::MoveInAbsoluteCoordinates(_)) => vec![GCode::RapidMove {
+				x: None,
+				y: None,
+				z: Some(stock_top + SAFE_Z),
+			}],
+			// Marlin doesn't support M2, so just stop everything that's running
+			(Flavor::Marlin, GCode::ProgramEnd) => self.rewrite(&GCode::SpindleStop, stock_top),
+			(_, GCode::SpindleOnCW { .. }) if self.fan_as_vacuum => vec![line.clone(), GCode::FanOn],
+			(_, GCode::SpindleStop) if self.fan_as_vacuum => vec![line.clone(), GCode::FanOff],
+			(_, GCode::OperationStart(operation)) => {
+				let mut lines = Vec::new();
+
+				if self.pause_operations {
+					lines.push(GCode::Comment(format!("Paused before {}", operation.name)));
+					lines.push(GCode::ProgramPause);
+				}
+
+				if self.operation_comments {
+					lines.push(GCode::Comment(operation.comment()));
+				}
+
+				lines
+			},
+			(_, GCode::OperationEnd(name)) if self.operation_comments => vec![GCode::Comment(format!("END OPERATION {}", name))],
+			(_, GCode::OperationEnd(_)) => Vec::new(),
+			_ => vec![line.clone()],
+		}
+	}
+
+	/// Returns a copy of line with its feed rate or spindle speed scaled.
+	fn scale_speeds(&self, line: &GCode) -> GCode {
+		let mut line = line.clone();
+
+		match &mut line {
+			GCode::LinearMove { feed, .. } | GCode::ClockwiseArc { feed, .. } | GCode::CounterClockwiseArc { feed, .. } => *feed *= self.feed_scale,
+			GCode::SpindleOnCW { rpm } => *rpm *= self.rpm_scale,
+			_ => {},
+		}
+
+		line
+	}
+}
+
+
+impl Postprocessor {
+	fn format_word(&self, word: GcodeWord) -> String {
+		let number = |n: f64| {
+			let s = format_number(n);
+
+			if self.decimal_point && !s.contains('.') {
+				s + "."
+			} else {
+				s
+			}
+		};
+
+		match word {
+			GcodeWord::G(n) if self.padded_codes => format!("G{:02}", n),
+			GcodeWord::G(n) => format!("G{}", n),
+			GcodeWord::M(n) => format!("M{:02}", n),
+			GcodeWord::F(n) => format!("F{}", number(n)),
+			GcodeWord::I(n) => format!("I{}", number(n)),
+			GcodeWord::J(n) => format!("J{}", number(n)),
+			GcodeWord::S(n) => format!("S{}", number(n)),
+			GcodeWord::X(n) => format!("X{}", number(n)),
+			GcodeWord::Y(n) => format!("Y{}", number(n)),
+			GcodeWord::Z(n) => format!("Z{}", number(n)),
+		}
+	}
+}
+
+
+impl MachineProfile {
+	/// Returns a copy of line with its feed rate limited to what the machine can do.
+	/// Every feed that had to be clamped is recorded in clamped once, with the lowest and highest feeds it was clamped to, since
+	/// the limit for moves with a Z component varies with their slope.
+	fn clamp_feed(&self, line: &GCode, state: &HashMap<char, f64>, clamped: &mut Vec<(f64, f64, f64)>) -> GCode {
+		let (feed, limit) = match line {
+			GCode::LinearMove { x, y, z, feed } => {
+				let delta = |axis: Option<f64>, c: char| match (axis, state.get(&c)) {
+					(Some(v), Some(current)) => Some(v - current),
+					(Some(_), None) => None,
+					(None, _) => Some(0.0),
+				};
+				let mut limit = self.max_feed.unwrap_or(f64::INFINITY);
+
+				if let Some(max_z_feed) = self.max_z_feed {
+					limit = match (delta(*x, 'X'), delta(*y, 'Y'), delta(*z, 'Z')) {
+						(_, _, Some(0.0)) => limit,
+						(Some(dx), Some(dy), Some(dz)) => limit.min(max_z_feed * (dx * dx + dy * dy + dz * dz).sqrt() / dz.abs()),
+						// Moves from an unknown position are treated as pure Z moves
+						_ => limit.min(max_z_feed),
+					};
+				}
+
+				(*feed, limit)
+			},
+			GCode::ClockwiseArc { feed, .. } | GCode::CounterClockwiseArc { feed, .. } => (*feed, self.max_feed.unwrap_or(f64::INFINITY)),
+			// Machine coordinates can't be related to the work position, so the move is clamped as if from an unknown position
+			GCode::MoveInAbsoluteCoordinates(line) => {
+				return GCode::MoveInAbsoluteCoordinates(Box::new(self.clamp_feed(line, &HashMap::new(), clamped)));
+			},
+			_ => return line.clone(),
+		};
+
+		if feed <= limit {
+			return line.clone();
+		}
+
+		match clamped.iter_mut().find(|(requested, _, _)| *requested == feed) {
+			Some((_, lowest, highest)) => {
+				*lowest = lowest.min(limit);
+				*highest = highest.max(limit);
+			},
+			None => clamped.push((feed, limit, limit)),
+		}
+
+		let mut line = line.clone();
+
+		match &mut line {
+			GCode::LinearMove { feed, .. } | GCode::ClockwiseArc { feed, .. } | GCode::CounterClockwiseArc { feed, .. } => *feed = limit,
+			_ => unreachable!(),
+		}
+
+		line
+	}
+}
+
+
+fn check_depth(depth: f64) -> Result<()> {
+	if depth <= 0.0 {
+		bail!("Depth must be positive (measured down from the top of the cut), got {}", format_number(depth));
+	}
+
+	Ok(())
+}
+
+
+/// Controllers vary in what they accept in comments, so they are written as printable ASCII only: other characters become ?
+/// and control characters become spaces.  Parentheses would end or nest a standard comment, so they become brackets.
+fn sanitize_comment(comment: &str, flavor: Flavor) -> String {
+	comment
+		.chars()
+		.map(|c| match c {
+			'(' if flavor == Flavor::Standard => '[',
+			')' if flavor == Flavor::Standard => ']',
+			c if c.is_ascii_control() => ' ',
+			c if c.is_ascii() => c,
+			_ => '?',
+		})
+		.collect()
+}
+
+
 fn format_number(f: f64) -> String {
 	let mut s = format!("{:.3}", f);
 	let t = s.trim_end_matches('0').trim_end_matches('.').len();
@@ -322,6 +1279,9 @@ fn format_number(f: f64) -> String {
 #[derive(PartialEq, Clone, Debug)]
 enum GCode {
 	Comment(String),
+	/// Markers around the code of an operation, which the postprocessor turns into comments or leaves out.
+	OperationStart(Box<Operation>),
+	OperationEnd(String),
 	RapidMove {
 		x: Option<f64>,
 		y: Option<f64>,
@@ -333,6 +1293,13 @@ enum GCode {
 		z: Option<f64>,
 		feed: f64,
 	}, // G1
+	ClockwiseArc {
+		x: f64,
+		y: f64,
+		cx: f64,
+		cy: f64,
+		feed: f64,
+	}, // G2
 	CounterClockwiseArc {
 		x: f64,
 		y: f64,
@@ -344,17 +1311,20 @@ enum GCode {
 	MoveInAbsoluteCoordinates(Box<Self>), // G53
 	AbsoluteDistanceMode,                 // G90
 
-	ProgramEnd, // M02
+	ProgramPause, // M00
+	ProgramEnd,   // M02
 	SpindleOnCW {
 		rpm: f64,
 	}, // M03
-	SpindleStop, // M05
+	SpindleStop,  // M05
+	FanOn,        // M106
+	FanOff,       // M107
 }
 
 #[derive(PartialEq, Clone, Debug, Copy)]
 enum GcodeWord {
 	G(u8),
-	M(u8),
+	M(u16),
 	F(f64),
 	I(f64),
 	J(f64),
@@ -364,6 +1334,40 @@ enum GcodeWord {
 	Z(f64),
 }
 
+/// Summary of an operation, for the comment that starts it.
+#[derive(PartialEq, Clone, Debug, Default)]
+struct Operation {
+	name: String,
+	cutter_diameter: f64,
+	/// Lowest Z the operation cuts to.
+	bottom: Option<f64>,
+	/// Area the center of the tool moves over, as [min_x, min_y, max_x, max_y].
+	bounds: Option<[f64; 4]>,
+}
+
+impl Operation {
+	fn comment(&self) -> String {
+		let mut comment = format!("OPERATION {} TOOL={}", self.name, format_number(self.cutter_diameter));
+
+		if let Some(bottom) = self.bottom {
+			comment += &format!(" BOTTOM={}", format_number(bottom));
+		}
+
+		if let Some([min_x, min_y, max_x, max_y]) = self.bounds {
+			comment += &format!(
+				" X={}:{} Y={}:{}",
+				format_number(min_x),
+				format_number(max_x),
+				format_number(min_y),
+				format_number(max_y)
+			);
+		}
+
+		comment
+	}
+}
+
+
 impl GCode {
 	fn to_words(&self, current_x: Option<f64>, current_y: Option<f64>) -> Result<Vec<GcodeWord>> {
 		Ok(match self {
@@ -381,10 +1385,12 @@ impl GCode {
 			.into_iter()
 			.flatten()
 			.collect(),
-			GCode::CounterClockwiseArc { x, y, cx, cy, feed } => {
+			GCode::ClockwiseArc { x, y, cx, cy, feed } | GCode::CounterClockwiseArc { x, y, cx, cy, feed } => {
+				let g = if matches!(self, GCode::ClockwiseArc { .. }) { 2 } else { 3 };
+
 				if let (Some(current_x), Some(current_y)) = (current_x, current_y) {
 					vec![
-						Some(GcodeWord::G(3)),
+						Some(GcodeWord::G(g)),
 						Some(GcodeWord::X(*x)),
 						Some(GcodeWord::Y(*y)),
 						Some(GcodeWord::I(*cx - current_x)),
@@ -395,7 +1401,7 @@ impl GCode {
 					.flatten()
 					.collect()
 				} else {
-					bail!("Cannot generate G3 arc without current position");
+					bail!("Cannot generate G{} arc without current position", g);
 				}
 			},
 			GCode::MetricUnits => vec![GcodeWord::G(21)],
@@ -405,10 +1411,13 @@ impl GCode {
 				words
 			},
 			GCode::AbsoluteDistanceMode => vec![GcodeWord::G(90)],
+			GCode::ProgramPause => vec![GcodeWord::M(0)],
 			GCode::ProgramEnd => vec![GcodeWord::M(2)],
 			GCode::SpindleOnCW { rpm } => vec![GcodeWord::M(3), GcodeWord::S(*rpm)],
 			GCode::SpindleStop => vec![GcodeWord::M(5)],
-			GCode::Comment(_) => unreachable!(),
+			GCode::FanOn => vec![GcodeWord::M(106)],
+			GCode::FanOff => vec![GcodeWord::M(107)],
+			GCode::Comment(_) | GCode::OperationStart(_) | GCode::OperationEnd(_) => unreachable!(),
 		})
 	}
 
@@ -418,34 +1427,33 @@ impl GCode {
 		let s_present = words.iter().any(|w| matches!(w, GcodeWord::S(_)));
 
 		match self {
-			GCode::Comment(_) => unreachable!(),
+			GCode::Comment(_) | GCode::OperationStart(_) | GCode::OperationEnd(_) => unreachable!(),
 			GCode::RapidMove { x: _, y: _, z: _ } => !pos_present,
 			GCode::LinearMove { x: _, y: _, z: _, feed: _ } => !pos_present,
-			GCode::CounterClockwiseArc {
+			GCode::ClockwiseArc {
+				x: _,
+				y: _,
+				cx: _,
+				cy: _,
+				feed: _,
+			}
+			| GCode::CounterClockwiseArc {
 				x: _,
 				y: _,
 				cx: _,
 				cy: _,
 				feed: _,
 			} => !pos_present,
-			GCode::MetricUnits | GCode::AbsoluteDistanceMode | GCode::ProgramEnd | GCode::SpindleStop | GCode::MoveInAbsoluteCoordinates(_) => false,
-			GCode::SpindleOnCW { rpm: _ } => !s_present,
-		}
-	}
-}
-
-impl ToString for GcodeWord {
-	fn to_string(&self) -> String {
-		match self {
-			GcodeWord::G(n) => format!("G{}", n),
-			GcodeWord::M(n) => format!("M{:02}", n),
-			GcodeWord::F(n) => format!("F{}", format_number(*n)),
-			GcodeWord::I(n) => format!("I{}", format_number(*n)),
-			GcodeWord::J(n) => format!("J{}", format_number(*n)),
-			GcodeWord::S(n) => format!("S{}", format_number(*n)),
-			GcodeWord::X(n) => format!("X{}", format_number(*n)),
-			GcodeWord::Y(n) => format!("Y{}", format_number(*n)),
-			GcodeWord::Z(n) => format!("Z{}", format_number(*n)),
+			GCode::MetricUnits
+			| GCode::AbsoluteDistanceMode
+			| GCode::ProgramPause
+			| GCode::ProgramEnd
+			| GCode::SpindleStop
+			| GCode::FanOn
+			| GCode::FanOff
+			| GCode::MoveInAbsoluteCoordinates(_) => false,
+			// After a spindle stop the M03 has to be repeated even if the speed didn't change
+			GCode::SpindleOnCW { rpm: _ } => !s_present && !words.contains(&GcodeWord::M(3)),
 		}
 	}
 }
@@ -465,3 +1473,320 @@ impl GcodeWord {
 		}
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rapid(x: Option<f64>, y: Option<f64>, z: Option<f64>) -> GCode {
+		GCode::RapidMove { x, y, z }
+	}
+
+	#[test]
+	fn missing_retract() {
+		let mut gcode = GcodeState::new();
+		gcode.stock_top = 5.0;
+		gcode.push(rapid(None, None, Some(1.0)));
+		gcode.push(rapid(Some(10.0), Some(0.0), None));
+
+		assert!(gcode.finish(Vec::new()).is_err());
+	}
+
+	#[test]
+	fn retract_checked_against_stock_top_at_the_time() {
+		let mut gcode = GcodeState::new();
+		gcode.push(rapid(None, None, Some(1.0)));
+		gcode.push(rapid(Some(10.0), Some(0.0), None));
+		// Raising the top afterwards, e.g. for a second part stacked on top, doesn't make the earlier rapid wrong
+		gcode.stock_top = 5.0;
+		gcode.retract();
+		gcode.push(rapid(Some(20.0), Some(0.0), None));
+
+		gcode.finish(Vec::new()).unwrap();
+	}
+
+	#[test]
+	fn marlin_safe_z_above_stock_top() {
+		let postprocessor = Postprocessor {
+			flavor: Flavor::Marlin,
+			..Postprocessor::default()
+		};
+		let safe_z = GCode::MoveInAbsoluteCoordinates(Box::new(rapid(None, None, Some(-5.0))));
+
+		assert_eq!(postprocessor.rewrite(&safe_z, 12.0), [rapid(None, None, Some(17.0))]);
+		assert_eq!(Postprocessor::default().rewrite(&safe_z, 12.0), [safe_z]);
+	}
+
+	#[test]
+	fn clamped_feeds_recorded_once_per_requested_feed() {
+		let machine = MachineProfile {
+			max_feed: Some(1000.0),
+			max_z_feed: Some(100.0),
+			..MachineProfile::default()
+		};
+		let state = HashMap::from([('X', 0.0), ('Y', 0.0), ('Z', 0.0)]);
+		let mut clamped = Vec::new();
+
+		for (x, z) in [(3.0, -4.0), (3.0, -4.0), (0.0, -1.0), (10.0, 0.0)] {
+			let line = GCode::LinearMove {
+				x: Some(x),
+				y: None,
+				z: Some(z),
+				feed: 2000.0,
+			};
+			machine.clamp_feed(&line, &state, &mut clamped);
+		}
+
+		assert_eq!(clamped, [(2000.0, 100.0, 1000.0)]);
+	}
+
+	#[test]
+	fn marlin_program_end_and_vacuum() {
+		let postprocessor = Postprocessor {
+			flavor: Flavor::Marlin,
+			fan_as_vacuum: true,
+			..Postprocessor::default()
+		};
+		let spindle_on = GCode::SpindleOnCW { rpm: 10000.0 };
+
+		assert_eq!(postprocessor.rewrite(&spindle_on, 0.0), [spindle_on, GCode::FanOn]);
+		assert_eq!(postprocessor.rewrite(&GCode::ProgramEnd, 0.0), [GCode::SpindleStop, GCode::FanOff]);
+		assert_eq!(Postprocessor::default().rewrite(&GCode::ProgramEnd, 0.0), [GCode::ProgramEnd]);
+	}
+
+	#[test]
+	fn operation_markers() {
+		let start = GCode::OperationStart(Box::new(Operation {
+			name: "drill".to_string(),
+			cutter_diameter: 3.0,
+			..Operation::default()
+		}));
+		let end = GCode::OperationEnd("drill".to_string());
+
+		assert_eq!(Postprocessor::default().rewrite(&start, 0.0), []);
+		assert_eq!(Postprocessor::default().rewrite(&end, 0.0), []);
+
+		let postprocessor = Postprocessor {
+			operation_comments: true,
+			pause_operations: true,
+			..Postprocessor::default()
+		};
+		assert_eq!(
+			postprocessor.rewrite(&start, 0.0),
+			[
+				GCode::Comment("Paused before drill".to_string()),
+				GCode::ProgramPause,
+				GCode::Comment("OPERATION drill TOOL=3".to_string())
+			]
+		);
+		assert_eq!(postprocessor.rewrite(&end, 0.0), [GCode::Comment("END OPERATION drill".to_string())]);
+	}
+
+	#[test]
+	fn output_quirks_and_scaling() {
+		let mut gcode = GcodeState::new();
+		gcode.postprocessor.padded_codes = true;
+		gcode.postprocessor.decimal_point = true;
+		gcode.postprocessor.feed_scale = 0.5;
+		gcode.postprocessor.rpm_scale = 0.5;
+		gcode.set_rpm(10000.0);
+		gcode.push(rapid(Some(10.0), Some(0.0), Some(5.0)));
+		gcode.push(GCode::LinearMove {
+			x: None,
+			y: None,
+			z: Some(-1.5),
+			feed: 300.0,
+		});
+
+		let mut output = Vec::new();
+		gcode.finish(&mut output).unwrap();
+
+		assert_eq!(String::from_utf8(output).unwrap(), "M03 S5000.\nG00 X10. Y0. Z5.\nG01 Z-1.5 F150.\nM02\n");
+	}
+
+	#[test]
+	fn rapids_as_feed_are_clamped() {
+		let mut gcode = GcodeState::new();
+		gcode.machine.max_feed = Some(1000.0);
+		gcode.machine.max_z_feed = Some(200.0);
+		gcode.machine.rapid_feed = Some(3000.0);
+		gcode.postprocessor.rapids_as_feed = true;
+		gcode.write_header();
+		gcode.push(rapid(None, None, Some(5.0)));
+		gcode.push(rapid(Some(10.0), Some(10.0), None));
+
+		let mut output = Vec::new();
+		gcode.finish(&mut output).unwrap();
+		let output = String::from_utf8(output).unwrap();
+
+		assert!(output.contains("G53 G1 Z-5 F200\n"), "{}", output);
+		assert!(output.contains("G1 Z5 F200\n"), "{}", output);
+		assert!(output.contains("X10 Y10 F1000\n"), "{}", output);
+		assert_eq!(gcode.warnings(), ["Feed rate 3000 exceeds the machine limits, clamped to between 200 and 1000"]);
+	}
+
+	#[test]
+	fn operation_cache_replays_once_only_warnings_once() {
+		let mut gcode = GcodeState::new();
+		gcode.cutter_diameter = 6.0;
+		gcode.depth_per_pass = 1.0;
+		gcode.feed_rate = 1000.0;
+		gcode.plunge_rate = 100.0;
+		gcode.machine.max_mrr = Some(1.0);
+		gcode.set_operation_cache(true);
+		gcode.push(rapid(Some(0.0), Some(0.0), Some(5.0)));
+
+		// Leaves the tool where it started, so each repeat runs from the same state and replays the first
+		for _ in 0..3 {
+			gcode
+				.operation("dip", &[2.0], |gcode| {
+					gcode.plunge(-2.0);
+					gcode.retract();
+					Ok(())
+				})
+				.unwrap();
+		}
+
+		assert_eq!(gcode.warnings().len(), 1, "{:?}", gcode.warnings());
+		assert_eq!(gcode.program.iter().filter(|code| matches!(code, GCode::OperationEnd(_))).count(), 3);
+	}
+
+	#[test]
+	fn pause_with_flip_mirrors_following_moves() {
+		let mut gcode = GcodeState::new();
+		assert!(gcode.pause("Flip", Some('y')).is_err());
+		assert!(gcode.program.is_empty());
+
+		gcode.stock = Some(Stock {
+			width: 100.0,
+			height: 50.0,
+			thickness: None,
+		});
+		gcode.feed_rate = 1000.0;
+		gcode.set_rpm(10000.0);
+		let start = gcode.program.len();
+		gcode.pause("Flip", Some('y')).unwrap();
+		gcode.cutting_move(10.0, 5.0, None);
+
+		let cut = GCode::LinearMove {
+			x: Some(90.0),
+			y: Some(5.0),
+			z: None,
+			feed: 1000.0,
+		};
+		assert_eq!(
+			gcode.program[start..],
+			[
+				rapid(None, None, Some(SAFE_Z)),
+				GCode::SpindleStop,
+				GCode::Comment("Flip".to_string()),
+				GCode::ProgramPause,
+				GCode::SpindleOnCW { rpm: 10000.0 },
+				cut
+			]
+		);
+	}
+
+	#[test]
+	fn flip_drills_pins_on_the_centerline() {
+		let mut gcode = GcodeState::new();
+		gcode.stock = Some(Stock {
+			width: 100.0,
+			height: 50.0,
+			thickness: None,
+		});
+		gcode.plunge_rate = 100.0;
+		// Pins ignore the current transformation, but it is mirrored afterwards
+		gcode.transformation = Matrix3::new_translation(&nalgebra::Vector2::new(5.0, 0.0));
+		gcode.flip('x', 12.0, 10.0).unwrap();
+		gcode.cutting_move(10.0, 5.0, None);
+
+		let pins = gcode.program.iter().filter_map(|code| match code {
+			GCode::RapidMove { x: Some(x), y: Some(y), .. } => Some((*x, *y)),
+			_ => None,
+		});
+		assert_eq!(pins.collect::<Vec<_>>(), [(10.0, 25.0), (90.0, 25.0)]);
+		assert!(gcode.program.contains(&GCode::ProgramPause));
+		assert!(gcode.program.contains(&GCode::LinearMove {
+			x: None,
+			y: None,
+			z: Some(-12.0),
+			feed: 100.0,
+		}));
+		assert!(matches!(
+			gcode.program.last(),
+			Some(GCode::LinearMove {
+				x: Some(15.0),
+				y: Some(45.0),
+				..
+			})
+		));
+		assert!(GcodeState::new().flip('x', 12.0, 10.0).is_err());
+	}
+
+	#[test]
+	fn axis_map() {
+		let swapped = AxisMap::parse("Y,X,Z").unwrap();
+		let reversed = AxisMap::parse(" x, -y ,+Z").unwrap();
+		let line = GCode::LinearMove {
+			x: Some(1.0),
+			y: Some(2.0),
+			z: None,
+			feed: 100.0,
+		};
+		let arc = GCode::ClockwiseArc {
+			x: 1.0,
+			y: 2.0,
+			cx: 0.0,
+			cy: 2.0,
+			feed: 100.0,
+		};
+
+		assert_eq!(AxisMap::parse("X,Y,Z").unwrap(), AxisMap::default());
+		assert_eq!(
+			swapped.apply(&line).unwrap(),
+			GCode::LinearMove {
+				x: Some(2.0),
+				y: Some(1.0),
+				z: None,
+				feed: 100.0,
+			}
+		);
+		assert_eq!(
+			reversed.apply(&rapid(Some(1.0), Some(2.0), Some(3.0))).unwrap(),
+			rapid(Some(1.0), Some(-2.0), Some(3.0))
+		);
+
+		// Mirroring the XY plane reverses arcs
+		assert_eq!(
+			reversed.apply(&arc).unwrap(),
+			GCode::CounterClockwiseArc {
+				x: 1.0,
+				y: -2.0,
+				cx: 0.0,
+				cy: -2.0,
+				feed: 100.0,
+			}
+		);
+		assert!(AxisMap::parse("Z,Y,X").unwrap().apply(&arc).is_err());
+
+		for spec in ["X,Y", "X,Y,Z,X", "X,X,Z", "X,Y,W"] {
+			assert!(AxisMap::parse(spec).is_err(), "{}", spec);
+		}
+	}
+
+	#[test]
+	fn marlin_spindle_power_on_every_m3() {
+		let mut gcode = GcodeState::new();
+		gcode.postprocessor.flavor = Flavor::Marlin;
+		gcode.set_rpm(10000.0);
+		gcode.pause("Check", None).unwrap();
+
+		let mut output = Vec::new();
+		gcode.finish(&mut output).unwrap();
+		let output = String::from_utf8(output).unwrap();
+
+		assert_eq!(output.matches("M03 S10000\n").count(), 2, "{}", output);
+	}
+}
@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+
+/// A grayscale image with brightness values from 0.0 (black) to 1.0 (white), stored row by row from the top.
+pub struct GrayImage {
+	pub width: usize,
+	pub height: usize,
+	pixels: Vec<f64>,
+}
+
+impl GrayImage {
+	/// Loads a binary (P5) or plain (P2) PGM image.
+	pub fn load_pgm<P: AsRef<Path>>(path: P) -> Result<GrayImage> {
+		let data = std::fs::read(path.as_ref()).with_context(|| format!("Failed to read image: {}", path.as_ref().display()))?;
+
+		GrayImage::parse_pgm(&data).with_context(|| format!("Failed to parse image: {}", path.as_ref().display()))
+	}
+
+	fn parse_pgm(data: &[u8]) -> Result<GrayImage> {
+		let mut pos = 0;
+		let magic = next_token(data, &mut pos)?;
+		let binary = match magic.as_str() {
+			"P5" => true,
+			"P2" => false,
+			_ => bail!("Only PGM images (P2 or P5) are supported"),
+		};
+
+		let width: usize = next_token(data, &mut pos)?.parse().context("Invalid width")?;
+		let height: usize = next_token(data, &mut pos)?.parse().context("Invalid height")?;
+		let maxval: u32 = next_token(data, &mut pos)?.parse().context("Invalid maximum value")?;
+
+		if width == 0 || height == 0 {
+			bail!("Image is empty");
+		}
+
+		if maxval == 0 || maxval > 65535 {
+			bail!("Invalid maximum value {}", maxval);
+		}
+
+		let n = width * height;
+		let pixels: Vec<u32> = if binary {
+			// A single whitespace byte separates the header from the pixel data
+			let data = &data[(pos + 1).min(data.len())..];
+			let bytes_per_pixel = if maxval > 255 { 2 } else { 1 };
+
+			if data.len() < n * bytes_per_pixel {
+				bail!("Image data is truncated");
+			}
+
+			data.chunks_exact(bytes_per_pixel)
+				.take(n)
+				.map(|chunk| chunk.iter().fold(0, |acc, &byte| (acc << 8) | byte as u32))
+				.collect()
+		} else {
+			(0..n)
+				.map(|_| next_token(data, &mut pos)?.parse::<u32>().context("Invalid pixel value"))
+				.collect::<Result<_>>()?
+		};
+
+		Ok(GrayImage {
+			width,
+			height,
+			pixels: pixels.into_iter().map(|p| p.min(maxval) as f64 / maxval as f64).collect(),
+		})
+	}
+
+	/// Brightness at (u, v), where both range from 0.0 to 1.0 and v = 0.0 is the top of the image.
+	pub fn sample(&self, u: f64, v: f64) -> f64 {
+		let x = ((u * self.width as f64) as usize).min(self.width - 1);
+		let y = ((v * self.height as f64) as usize).min(self.height - 1);
+
+		self.pixels[y * self.width + x]
+	}
+}
+
+
+/// Reads the next whitespace separated header token, skipping # comments.
+fn next_token(data: &[u8], pos: &mut usize) -> Result<String> {
+	loop {
+		match data.get(*pos) {
+			Some(b'#') => {
+				while data.get(*pos).is_some_and(|&c| c != b'\n') {
+					*pos += 1;
+				}
+			},
+			Some(c) if c.is_ascii_whitespace() => *pos += 1,
+			Some(_) => break,
+			None => bail!("Unexpected end of image"),
+		}
+	}
+
+	let start = *pos;
+
+	while data.get(*pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+		*pos += 1;
+	}
+
+	Ok(String::from_utf8_lossy(&data[start..*pos]).into_owned())
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn plain_pgm() {
+		let image = GrayImage::parse_pgm(b"P2\n# 2x2 gradient\n2 2\n4\n0 1\n2 9\n").unwrap();
+
+		assert_eq!((image.width, image.height), (2, 2));
+		assert_eq!(image.sample(0.0, 0.0), 0.0);
+		assert_eq!(image.sample(0.99, 0.0), 0.25);
+		assert_eq!(image.sample(0.0, 1.0), 0.5);
+		// Values above the maximum are clamped
+		assert_eq!(image.sample(1.0, 1.0), 1.0);
+	}
+
+	#[test]
+	fn binary_pgm() {
+		let image = GrayImage::parse_pgm(b"P5 2 1 255\n\x00\xff").unwrap();
+		assert_eq!([image.sample(0.0, 0.0), image.sample(1.0, 0.0)], [0.0, 1.0]);
+
+		let image = GrayImage::parse_pgm(b"P5 1 1 65535\n\x80\x00").unwrap();
+		assert_eq!(image.sample(0.0, 0.0), 32768.0 / 65535.0);
+
+		assert!(GrayImage::parse_pgm(b"P5 2 2 255\n\x00\xff").is_err());
+		assert!(GrayImage::parse_pgm(b"P6 1 1 255\n\x00\x00\x00").is_err());
+	}
+}
@@ -1,8 +1,14 @@
+mod cancel;
 mod engine;
+mod format;
 mod gcode;
+mod image;
 mod numbers;
+mod qr;
+mod random;
 mod value;
 
-pub use engine::ScriptEngine;
+pub use cancel::CancellationToken;
+pub use engine::{Progress, ScriptEngine, Snapshot, Timings, DEFAULT_MAX_CALL_DEPTH};
 
 pub const BUILTIN_MATERIALS: &str = include_str!("../materials.gcad");
@@ -1,4 +1,6 @@
 use std::{
+	cmp::Ordering,
+	fmt,
 	ops::{Add, Div, Mul, Neg, Sub},
 	str::FromStr,
 };
@@ -20,6 +22,8 @@ pub enum Unit {
 	FT,
 	IN,
 	YD,
+	Deg,
+	Rad,
 	None,
 }
 
@@ -34,11 +38,44 @@ impl FromStr for Unit {
 			"ft" => Ok(Unit::FT),
 			"in" => Ok(Unit::IN),
 			"yd" => Ok(Unit::YD),
+			"deg" => Ok(Unit::Deg),
+			"rad" => Ok(Unit::Rad),
 			_ => Err(()),
 		}
 	}
 }
 
+impl fmt::Display for Unit {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Unit::MM => "mm",
+			Unit::CM => "cm",
+			Unit::M => "m",
+			Unit::FT => "ft",
+			Unit::IN => "in",
+			Unit::YD => "yd",
+			Unit::Deg => "deg",
+			Unit::Rad => "rad",
+			Unit::None => "",
+		})
+	}
+}
+
+impl Unit {
+	pub fn is_length(self) -> bool {
+		matches!(self, Unit::MM | Unit::CM | Unit::M | Unit::FT | Unit::IN | Unit::YD)
+	}
+
+	pub fn is_angle(self) -> bool {
+		matches!(self, Unit::Deg | Unit::Rad)
+	}
+
+	/// Both lengths, both angles, or both unitless, so that one can be converted to the other.
+	pub fn same_kind(self, other: Unit) -> bool {
+		self.is_length() == other.is_length() && self.is_angle() == other.is_angle()
+	}
+}
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum InnerValue {
 	Integer(i64),
@@ -55,7 +92,11 @@ impl InnerValue {
 
 	pub fn pow(self, other: InnerValue) -> InnerValue {
 		match (self, other) {
-			(InnerValue::Integer(i), InnerValue::Integer(j)) => InnerValue::Integer(i.pow(j as u32)),
+			// Negative or overflowing integer powers fall back to floats
+			(InnerValue::Integer(i), InnerValue::Integer(j)) => match u32::try_from(j).ok().and_then(|j| i.checked_pow(j)) {
+				Some(value) => InnerValue::Integer(value),
+				None => InnerValue::Float((i as f64).powf(j as f64)),
+			},
 			(InnerValue::Integer(i), InnerValue::Float(j)) => InnerValue::Float((i as f64).powf(j)),
 			(InnerValue::Float(i), InnerValue::Float(j)) => InnerValue::Float(i.powf(j)),
 			(InnerValue::Float(i), InnerValue::Integer(j)) => InnerValue::Float(i.powf(j as f64)),
@@ -217,6 +258,14 @@ impl Number {
 			(Unit::YD, Unit::IN) => InnerValue::Float(value * 3.0 * 12.0),
 			(Unit::YD, Unit::FT) => InnerValue::Float(value * 3.0),
 			(Unit::YD, Unit::YD) => self.value,
+
+			(Unit::Deg, Unit::Deg) => self.value,
+			(Unit::Deg, Unit::Rad) => InnerValue::Float(value.to_radians()),
+			(Unit::Rad, Unit::Deg) => InnerValue::Float(value.to_degrees()),
+			(Unit::Rad, Unit::Rad) => self.value,
+
+			// Lengths and angles don't convert into each other; callers check can_mix first
+			_ => return *self,
 		};
 
 		Number { value, unit }
@@ -237,6 +286,18 @@ impl Number {
 			unit: self.unit,
 		}
 	}
+
+	/// Lengths and angles can't be combined, but either can be combined with a unitless number.
+	pub fn can_mix(&self, other: &Number) -> bool {
+		self.unit == Unit::None || other.unit == Unit::None || self.unit.same_kind(other.unit)
+	}
+
+	/// Compares two numbers after converting them to the same unit, so 1in > 20mm.
+	pub fn compare(&self, other: &Number) -> Option<Ordering> {
+		let (lhs, rhs) = convert_units_for_math(self, other);
+
+		lhs.value.as_float().partial_cmp(&rhs.value.as_float())
+	}
 }
 
 fn convert_units_for_math(lhs: &Number, rhs: &Number) -> (Number, Number) {
@@ -282,6 +343,16 @@ impl Neg for Number {
 	}
 }
 
+/// Formats like a literal in a script, e.g. 5mm or 2.5.
+impl fmt::Display for Number {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.value {
+			InnerValue::Integer(i) => write!(f, "{}{}", i, self.unit),
+			InnerValue::Float(v) => write!(f, "{}{}", v, self.unit),
+		}
+	}
+}
+
 impl TryFrom<ScriptValue> for Number {
 	type Error = &'static str;
 
@@ -0,0 +1,491 @@
+use anyhow::{bail, Result};
+
+
+/// A QR code symbol, encoded in byte mode at error correction level M.  Only versions 1 to 10 (up to 213 bytes) are supported.
+pub struct QrCode {
+	pub size: usize,
+	modules: Vec<bool>,
+	/// Finder, timing, alignment and format modules, which data and masks must not touch.
+	function: Vec<bool>,
+}
+
+/// For each version: error correction codewords per block, and the (count, data codewords) of each group of blocks.
+const BLOCKS: [(usize, [(usize, usize); 2]); 10] = [
+	(10, [(1, 16), (0, 0)]),
+	(16, [(1, 28), (0, 0)]),
+	(26, [(1, 44), (0, 0)]),
+	(18, [(2, 32), (0, 0)]),
+	(24, [(2, 43), (0, 0)]),
+	(16, [(4, 27), (0, 0)]),
+	(18, [(4, 31), (0, 0)]),
+	(22, [(2, 38), (2, 39)]),
+	(22, [(3, 36), (2, 37)]),
+	(26, [(4, 43), (1, 44)]),
+];
+
+const ALIGNMENT_POSITIONS: [&[usize]; 10] = [
+	&[],
+	&[6, 18],
+	&[6, 22],
+	&[6, 26],
+	&[6, 30],
+	&[6, 34],
+	&[6, 22, 38],
+	&[6, 24, 42],
+	&[6, 26, 46],
+	&[6, 28, 50],
+];
+
+impl QrCode {
+	/// Encodes data in the smallest version that fits, choosing the mask with the lowest penalty like a reader expects.
+	pub fn encode(data: &[u8]) -> Result<QrCode> {
+		let Some(version) = (1..=10).find(|&version| data_bits(version, data.len()) <= data_codewords(version) * 8) else {
+			bail!("Too much data for a QR code; at most 213 bytes fit");
+		};
+
+		let mut qr = QrCode {
+			size: 17 + 4 * version,
+			modules: vec![false; (17 + 4 * version).pow(2)],
+			function: vec![false; (17 + 4 * version).pow(2)],
+		};
+
+		qr.draw_function_patterns(version);
+		qr.draw_codewords(&add_error_correction(version, &data_codewords_for(version, data)));
+
+		let mut best = (usize::MAX, 0);
+		for mask in 0..8 {
+			qr.apply_mask(mask);
+			qr.draw_format(mask);
+			best = best.min((qr.penalty(), mask));
+			qr.apply_mask(mask);
+		}
+
+		qr.apply_mask(best.1);
+		qr.draw_format(best.1);
+
+		Ok(qr)
+	}
+
+	/// Whether the module at column x and row y, counting from the top left, is dark.
+	pub fn is_dark(&self, x: usize, y: usize) -> bool {
+		self.modules[y * self.size + x]
+	}
+
+	fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+		self.modules[y * self.size + x] = dark;
+		self.function[y * self.size + x] = true;
+	}
+
+	fn draw_function_patterns(&mut self, version: usize) {
+		let size = self.size;
+
+		for i in 0..size {
+			self.set_function(6, i, i % 2 == 0);
+			self.set_function(i, 6, i % 2 == 0);
+		}
+
+		// Finders, with their light separators, in three corners
+		for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+			for dy in -4..=4_isize {
+				for dx in -4..=4_isize {
+					let (x, y) = (cx as isize + dx, cy as isize + dy);
+					let distance = dx.abs().max(dy.abs());
+
+					if (0..size as isize).contains(&x) && (0..size as isize).contains(&y) {
+						self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+					}
+				}
+			}
+		}
+
+		// Alignment patterns go everywhere on the grid of positions except over the finders
+		let positions = ALIGNMENT_POSITIONS[version - 1];
+		for (i, &cy) in positions.iter().enumerate() {
+			for (j, &cx) in positions.iter().enumerate() {
+				let last = positions.len() - 1;
+				if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+					continue;
+				}
+
+				for dy in -2..=2_isize {
+					for dx in -2..=2_isize {
+						self.set_function((cx as isize + dx) as usize, (cy as isize + dy) as usize, dx.abs().max(dy.abs()) != 1);
+					}
+				}
+			}
+		}
+
+		// Reserve the format areas until the mask is known
+		self.draw_format(0);
+
+		if version >= 7 {
+			let mut remainder = version;
+			for _ in 0..12 {
+				remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+			}
+			let bits = version << 12 | remainder;
+
+			for i in 0..18 {
+				let dark = (bits >> i) & 1 != 0;
+				let (a, b) = (size - 11 + i % 3, i / 3);
+				self.set_function(a, b, dark);
+				self.set_function(b, a, dark);
+			}
+		}
+	}
+
+	/// Writes the error correction level and mask, twice, around the finders.
+	fn draw_format(&mut self, mask: usize) {
+		let size = self.size;
+		// Level M is 0b00
+		let data = mask;
+		let mut remainder = data;
+		for _ in 0..10 {
+			remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+		}
+		let bits = (data << 10 | remainder) ^ 0x5412;
+		let bit = |i: usize| (bits >> i) & 1 != 0;
+
+		for i in 0..6 {
+			self.set_function(8, i, bit(i));
+		}
+		self.set_function(8, 7, bit(6));
+		self.set_function(8, 8, bit(7));
+		self.set_function(7, 8, bit(8));
+		for i in 9..15 {
+			self.set_function(14 - i, 8, bit(i));
+		}
+
+		for i in 0..8 {
+			self.set_function(size - 1 - i, 8, bit(i));
+		}
+		for i in 8..15 {
+			self.set_function(8, size - 15 + i, bit(i));
+		}
+		self.set_function(8, size - 8, true);
+	}
+
+	/// Places the codewords in two module wide columns, zigzagging up and down from the right.
+	fn draw_codewords(&mut self, codewords: &[u8]) {
+		let size = self.size;
+		let mut i = 0;
+		let mut right = size - 1;
+
+		loop {
+			// The vertical timing pattern takes up a whole column
+			if right == 6 {
+				right = 5;
+			}
+
+			for vertical in 0..size {
+				for x in [right, right - 1] {
+					let upward = (right + 1) & 2 == 0;
+					let y = if upward { size - 1 - vertical } else { vertical };
+
+					if !self.function[y * size + x] && i < codewords.len() * 8 {
+						self.modules[y * size + x] = (codewords[i / 8] >> (7 - i % 8)) & 1 != 0;
+						i += 1;
+					}
+				}
+			}
+
+			if right < 3 {
+				break;
+			}
+			right -= 2;
+		}
+	}
+
+	/// Inverts the data modules picked out by the mask.  Applying the same mask again undoes it.
+	fn apply_mask(&mut self, mask: usize) {
+		for y in 0..self.size {
+			for x in 0..self.size {
+				let invert = match mask {
+					0 => (x + y) % 2 == 0,
+					1 => y % 2 == 0,
+					2 => x % 3 == 0,
+					3 => (x + y) % 3 == 0,
+					4 => (x / 3 + y / 2) % 2 == 0,
+					5 => x * y % 2 + x * y % 3 == 0,
+					6 => (x * y % 2 + x * y % 3) % 2 == 0,
+					_ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+				};
+
+				if invert && !self.function[y * self.size + x] {
+					self.modules[y * self.size + x] ^= true;
+				}
+			}
+		}
+	}
+
+	/// How hard the symbol is to read: long runs, 2x2 blocks, finder lookalikes, and an uneven balance of dark and light.
+	fn penalty(&self) -> usize {
+		let size = self.size;
+		let mut penalty = 0;
+		let finder_like = [true, false, true, true, true, false, true, false, false, false, false];
+
+		for transpose in [false, true] {
+			let line = |i: usize| -> Vec<bool> { (0..size).map(|j| if transpose { self.is_dark(i, j) } else { self.is_dark(j, i) }).collect() };
+
+			for i in 0..size {
+				let line = line(i);
+
+				for run in line.chunk_by(|a, b| a == b) {
+					if run.len() >= 5 {
+						penalty += run.len() - 2;
+					}
+				}
+
+				for window in line.windows(finder_like.len()) {
+					if window.iter().eq(finder_like.iter()) || window.iter().eq(finder_like.iter().rev()) {
+						penalty += 40;
+					}
+				}
+			}
+		}
+
+		for y in 0..size - 1 {
+			for x in 0..size - 1 {
+				let dark = self.is_dark(x, y);
+				if self.is_dark(x + 1, y) == dark && self.is_dark(x, y + 1) == dark && self.is_dark(x + 1, y + 1) == dark {
+					penalty += 3;
+				}
+			}
+		}
+
+		let dark = self.modules.iter().filter(|&&dark| dark).count();
+		penalty + (dark * 20).abs_diff(self.modules.len() * 10).div_ceil(self.modules.len()).saturating_sub(1) * 10
+	}
+}
+
+
+fn data_codewords(version: usize) -> usize {
+	BLOCKS[version - 1].1.iter().map(|&(count, codewords)| count * codewords).sum()
+}
+
+
+/// Bits needed for the mode, length and data of a byte mode segment.
+fn data_bits(version: usize, len: usize) -> usize {
+	let length_bits = if version < 10 { 8 } else { 16 };
+
+	if len >= 1 << length_bits {
+		return usize::MAX;
+	}
+
+	4 + length_bits + 8 * len
+}
+
+
+/// The data segment, terminated and padded out to fill the version's data codewords.
+fn data_codewords_for(version: usize, data: &[u8]) -> Vec<u8> {
+	let capacity = data_codewords(version) * 8;
+	let mut bits = Vec::with_capacity(capacity);
+	let mut push = |value: usize, len: usize| bits.extend((0..len).rev().map(|i| (value >> i) & 1 != 0));
+
+	push(0b0100, 4);
+	push(data.len(), if version < 10 { 8 } else { 16 });
+	for &byte in data {
+		push(byte as usize, 8);
+	}
+
+	let terminator = (capacity - bits.len()).min(4);
+	bits.resize(bits.len() + terminator, false);
+	bits.resize(bits.len().div_ceil(8) * 8, false);
+
+	let mut codewords = bits
+		.chunks(8)
+		.map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8))
+		.collect::<Vec<_>>();
+	for pad in [0xec, 0x11].into_iter().cycle() {
+		if codewords.len() >= capacity / 8 {
+			break;
+		}
+		codewords.push(pad);
+	}
+
+	codewords
+}
+
+
+/// Splits the data into blocks, adds Reed-Solomon codewords to each, and interleaves them.
+fn add_error_correction(version: usize, data: &[u8]) -> Vec<u8> {
+	let (ec_len, groups) = BLOCKS[version - 1];
+	let divisor = reed_solomon_divisor(ec_len);
+	let mut blocks = Vec::new();
+	let mut rest = data;
+
+	for (count, len) in groups {
+		for _ in 0..count {
+			let (block, remaining) = rest.split_at(len);
+			blocks.push((block, reed_solomon_remainder(block, &divisor)));
+			rest = remaining;
+		}
+	}
+
+	let max_len = blocks.iter().map(|(block, _)| block.len()).max().unwrap_or(0);
+	let mut result = Vec::new();
+
+	for i in 0..max_len {
+		result.extend(blocks.iter().filter_map(|(block, _)| block.get(i)));
+	}
+	for i in 0..ec_len {
+		result.extend(blocks.iter().map(|(_, ec)| ec[i]));
+	}
+
+	result
+}
+
+
+/// Coefficients of the generator polynomial of the given degree, highest first, leaving out the leading 1.
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+	let mut result = vec![0; degree - 1];
+	result.push(1);
+	let mut root = 1;
+
+	for _ in 0..degree {
+		for j in 0..degree {
+			result[j] = gf_multiply(result[j], root);
+			if j + 1 < degree {
+				result[j] ^= result[j + 1];
+			}
+		}
+		root = gf_multiply(root, 0x02);
+	}
+
+	result
+}
+
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+	let mut result = vec![0; divisor.len()];
+
+	for &byte in data {
+		let factor = byte ^ result.remove(0);
+		result.push(0);
+
+		for (x, &y) in result.iter_mut().zip(divisor) {
+			*x ^= gf_multiply(y, factor);
+		}
+	}
+
+	result
+}
+
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn gf_multiply(x: u8, y: u8) -> u8 {
+	let mut z: u32 = 0;
+
+	for i in (0..8).rev() {
+		z = (z << 1) ^ ((z >> 7) * 0x11d);
+		z ^= ((y as u32 >> i) & 1) * x as u32;
+	}
+
+	z as u8
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn module_bits(qr: &QrCode, positions: impl Iterator<Item = (usize, usize)>) -> usize {
+		positions.enumerate().fold(0, |bits, (i, (x, y))| bits | (qr.is_dark(x, y) as usize) << i)
+	}
+
+	#[test]
+	fn hello_world() {
+		// Byte mode, mask 4
+		let expected = [
+			"#######.##..#.#######",
+			"#.....#....#..#.....#",
+			"#.###.#..#.#..#.###.#",
+			"#.###.#.#..#..#.###.#",
+			"#.###.#.###.#.#.###.#",
+			"#.....#.#..#..#.....#",
+			"#######.#.#.#.#######",
+			"........#..##........",
+			"#...#.######.#####..#",
+			"...#....#.###....####",
+			"..######..##.##.#..#.",
+			"#####...##...#.......",
+			"#####.#.#.#.#.##..##.",
+			"........#.#.####.#.##",
+			"#######.###.#.#.##.#.",
+			"#.....#..#.###.##..##",
+			"#.###.#.##.#.##...##.",
+			"#.###.#..#..#...##.##",
+			"#.###.#..###...###...",
+			"#.....#....#.#.......",
+			"#######.#########.#.#",
+		];
+		let qr = QrCode::encode(b"HELLO WORLD").unwrap();
+
+		assert_eq!(qr.size, 21);
+		for (y, row) in expected.iter().enumerate() {
+			let actual = (0..qr.size).map(|x| if qr.is_dark(x, y) { '#' } else { '.' }).collect::<String>();
+			assert_eq!(actual, *row, "row {}", y);
+		}
+	}
+
+	#[test]
+	fn reed_solomon() {
+		// HELLO WORLD at 1-M in alphanumeric mode, from the worked example at thonky.com
+		let data = [32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17];
+		let ec = [196, 35, 39, 119, 235, 215, 231, 226, 93, 23];
+
+		assert_eq!(reed_solomon_remainder(&data, &reed_solomon_divisor(10)), ec);
+	}
+
+	#[test]
+	fn format_bits() {
+		// Level M for each mask, from the format information table in ISO 18004
+		let expected = [
+			0b101010000010010,
+			0b101000100100101,
+			0b101111001111100,
+			0b101101101001011,
+			0b100010111111001,
+			0b100000011001110,
+			0b100111110010111,
+			0b100101010100000,
+		];
+		let mut qr = QrCode::encode(b"format").unwrap();
+		let size = qr.size;
+
+		for (mask, &expected) in expected.iter().enumerate() {
+			qr.draw_format(mask);
+
+			let around_top_left = (0..6).map(|i| (8, i)).chain([(8, 7), (8, 8), (7, 8)]).chain((9..15).map(|i| (14 - i, 8)));
+			let split = (0..8).map(|i| (size - 1 - i, 8)).chain((8..15).map(|i| (8, size - 15 + i)));
+			assert_eq!(module_bits(&qr, around_top_left), expected, "mask {}", mask);
+			assert_eq!(module_bits(&qr, split), expected, "mask {}", mask);
+		}
+	}
+
+	#[test]
+	fn version_information() {
+		// Byte capacity at level M for versions 7 to 10, and their version information from ISO 18004
+		for (len, version, expected) in [(122, 7, 0x07c94), (152, 8, 0x085bc), (180, 9, 0x09a99), (213, 10, 0x0a4d3)] {
+			let qr = QrCode::encode(&vec![b'a'; len]).unwrap();
+			let size = qr.size;
+
+			assert_eq!(size, 17 + 4 * version);
+			let top_right = (0..18).map(|i| (size - 11 + i % 3, i / 3));
+			let bottom_left = (0..18).map(|i| (i / 3, size - 11 + i % 3));
+			assert_eq!(module_bits(&qr, top_right), expected, "version {}", version);
+			assert_eq!(module_bits(&qr, bottom_left), expected, "version {}", version);
+		}
+	}
+
+	#[test]
+	fn capacity() {
+		// Byte capacity at level M for versions 1 to 6
+		for (version, len) in [14, 26, 42, 62, 84, 106].into_iter().enumerate() {
+			assert_eq!(QrCode::encode(&vec![0; len]).unwrap().size, 21 + 4 * version);
+			assert_eq!(QrCode::encode(&vec![0; len + 1]).unwrap().size, 25 + 4 * version);
+		}
+
+		assert!(QrCode::encode(&[0; 214]).is_err());
+	}
+}
@@ -1,41 +1,175 @@
-use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::{
+	cmp::Ordering,
+	fmt,
+	ops::{Add, Div, Mul, Neg, Sub},
+};
 
-use crate::numbers::Number;
+use anyhow::{bail, Result};
+
+use crate::numbers::{InnerValue, Number, Unit};
 
 
 #[derive(Debug, Clone)]
 pub enum ScriptValue {
 	Number(Number),
 	String(String),
-	Range { start: Number, step: Number, num: usize },
+	Bool(bool),
+	List(Vec<ScriptValue>),
+	Range {
+		start: Number,
+		step: Number,
+		num: usize,
+	},
+	/// A user function, referred to by name, that can be passed around and called.
+	Function(String),
 	Null,
 }
 
 impl ScriptValue {
-	pub fn pow(&self, other: &ScriptValue) -> ScriptValue {
+	/// Whether the value counts as true in a condition: non-zero numbers, non-empty strings and ranges.
+	pub fn is_truthy(&self) -> bool {
+		match self {
+			ScriptValue::Number(n) => f64::from(*n) != 0.0,
+			ScriptValue::String(s) => !s.is_empty(),
+			ScriptValue::Bool(b) => *b,
+			ScriptValue::List(items) => !items.is_empty(),
+			ScriptValue::Range { num, .. } => *num > 0,
+			ScriptValue::Function(_) => true,
+			ScriptValue::Null => false,
+		}
+	}
+
+	/// Name of the value's type for error messages.
+	pub fn type_name(&self) -> &'static str {
+		match self {
+			ScriptValue::Number(_) => "number",
+			ScriptValue::String(_) => "string",
+			ScriptValue::Bool(_) => "boolean",
+			ScriptValue::List(_) => "list",
+			ScriptValue::Range { .. } => "range",
+			ScriptValue::Function(_) => "function",
+			ScriptValue::Null => "null",
+		}
+	}
+
+	/// Items to loop over: the numbers of a range, the items of a list, or the characters of a string.
+	pub fn into_items(self) -> Result<Box<dyn Iterator<Item = ScriptValue>>> {
+		Ok(match self {
+			ScriptValue::Range { start, step, num } => Box::new((0..num).map(move |i| ScriptValue::Number(start + step * (i as i64).into()))),
+			ScriptValue::List(items) => Box::new(items.into_iter()),
+			ScriptValue::String(s) => Box::new(s.chars().map(|c| ScriptValue::String(c.to_string())).collect::<Vec<_>>().into_iter()),
+			_ => bail!("Cannot loop over a {}", self.type_name()),
+		})
+	}
+
+	pub fn pow(&self, other: &ScriptValue) -> Result<ScriptValue> {
 		match (self, other) {
-			(ScriptValue::Number(a), ScriptValue::Number(b)) => ScriptValue::Number(a.pow(b)),
-			_ => panic!("Cannot do math on non-numbers"),
+			(ScriptValue::Number(a), ScriptValue::Number(b)) if !a.can_mix(b) => {
+				bail!("Cannot raise {} to the power of {}; lengths and angles don't mix", a, b)
+			},
+			(ScriptValue::Number(a), ScriptValue::Number(b)) => Ok(ScriptValue::Number(a.pow(b))),
+			_ => bail!("Cannot raise a {} to the power of a {}", self.type_name(), other.type_name()),
+		}
+	}
+
+	/// Evenly spaced numbers from start towards stop, for range literals.  Step defaults to 1 in the unit of the range, and
+	/// may be negative to count down.
+	pub fn range(start: ScriptValue, stop: ScriptValue, step: Option<ScriptValue>, inclusive: bool) -> Result<ScriptValue> {
+		let (ScriptValue::Number(start), ScriptValue::Number(stop)) = (&start, &stop) else {
+			bail!("Cannot make a range from a {} to a {}", start.type_name(), stop.type_name());
+		};
+		if !start.can_mix(stop) {
+			bail!("Cannot make a range from {} to {}; lengths and angles don't mix", start, stop);
+		}
+		let unit = if start.unit == Unit::None { stop.unit } else { start.unit };
+		let step = match step {
+			Some(ScriptValue::Number(step)) if !step.can_mix(start) || !step.can_mix(stop) => {
+				bail!("Cannot step from {} to {} by {}; lengths and angles don't mix", start, stop, step)
+			},
+			Some(ScriptValue::Number(step)) => step,
+			Some(step) => bail!("Range step must be a number, not a {}", step.type_name()),
+			None => Number {
+				value: InnerValue::Integer(1),
+				unit,
+			},
+		};
+		let (start, stop, step) = (start.convert_unit(unit), stop.convert_unit(unit), step.convert_unit(unit));
+
+		if f64::from(step) == 0.0 {
+			bail!("Range step must not be zero");
+		}
+
+		// Allow for rounding error so that e.g. 0..=1 step 0.1 includes 1
+		let steps = f64::from(stop - start) / f64::from(step);
+		let num = match inclusive {
+			true => (steps + 1e-9).floor() + 1.0,
+			false => (steps - 1e-9).ceil(),
+		};
+
+		if !num.is_finite() {
+			bail!("Range is not finite");
+		}
+
+		Ok(ScriptValue::Range {
+			start,
+			step,
+			num: num.max(0.0) as usize,
+		})
+	}
+
+	/// Orders two numbers (unit-aware), strings or booleans.
+	pub fn compare(&self, other: &ScriptValue) -> Result<Ordering> {
+		match (self, other) {
+			(ScriptValue::Number(a), ScriptValue::Number(b)) if !a.can_mix(b) => bail!("Cannot compare {} and {}; lengths and angles don't mix", a, b),
+			(ScriptValue::Number(a), ScriptValue::Number(b)) => match a.compare(b) {
+				Some(ordering) => Ok(ordering),
+				None => bail!("Cannot compare NaN"),
+			},
+			(ScriptValue::String(a), ScriptValue::String(b)) => Ok(a.cmp(b)),
+			(ScriptValue::Bool(a), ScriptValue::Bool(b)) => Ok(a.cmp(b)),
+			_ => bail!("Can only compare two numbers, strings or booleans"),
+		}
+	}
+
+	/// Element of a list, counting from 0.
+	pub fn index(&self, index: &ScriptValue) -> Result<ScriptValue> {
+		let items = match self {
+			ScriptValue::List(items) => items,
+			_ => bail!("Only lists can be indexed"),
+		};
+
+		let i = match index {
+			ScriptValue::Number(Number {
+				value: InnerValue::Integer(i),
+				unit: Unit::None,
+			}) => *i,
+			_ => bail!("List index must be a unitless integer"),
+		};
+
+		match usize::try_from(i).ok().and_then(|i| items.get(i)) {
+			Some(item) => Ok(item.clone()),
+			None => bail!("List index {} is out of range for a list of length {}", i, items.len()),
 		}
 	}
 
-	pub fn factorial(&self) -> ScriptValue {
+	pub fn factorial(&self) -> Result<ScriptValue> {
 		match self {
-			ScriptValue::Number(a) => ScriptValue::Number(a.factorial()),
-			_ => panic!("Cannot do math on non-numbers"),
+			ScriptValue::Number(a) => Ok(ScriptValue::Number(a.factorial())),
+			_ => bail!("Cannot take the factorial of a {}", self.type_name()),
 		}
 	}
 }
 
 macro_rules! math_impl {
-	($($t:ty,$i:ident,$op:ident)*) => ($(
+	($($t:ty,$i:ident,$op:ident,$verb:literal)*) => ($(
 		impl $i for $t {
-			type Output = ScriptValue;
+			type Output = Result<ScriptValue>;
 
-			fn $op(self, other: $t) -> ScriptValue {
+			fn $op(self, other: $t) -> Result<ScriptValue> {
 				match (&self, &other) {
-					(ScriptValue::Number(a), ScriptValue::Number(b)) => ScriptValue::Number(Number::$op(*a, *b)),
-					_ => panic!("Cannot do math on non-numbers"),
+					(ScriptValue::Number(a), ScriptValue::Number(b)) if !a.can_mix(b) => bail!("Cannot {} {} and {}; lengths and angles don't mix", $verb, a, b),
+					(ScriptValue::Number(a), ScriptValue::Number(b)) => Ok(ScriptValue::Number(Number::$op(*a, *b))),
+					_ => bail!("Cannot {} a {} and a {}", $verb, self.type_name(), other.type_name()),
 				}
 			}
 		}
@@ -43,19 +177,46 @@ macro_rules! math_impl {
 }
 
 math_impl! {
-	ScriptValue, Add, add
-	ScriptValue, Sub, sub
-	ScriptValue, Mul, mul
-	ScriptValue, Div, div
+	ScriptValue, Add, add, "add"
+	ScriptValue, Sub, sub, "subtract"
+	ScriptValue, Mul, mul, "multiply"
+	ScriptValue, Div, div, "divide"
 }
 
 impl Neg for ScriptValue {
-	type Output = ScriptValue;
+	type Output = Result<ScriptValue>;
+
+	fn neg(self) -> Result<ScriptValue> {
+		match self {
+			ScriptValue::Number(a) => Ok(ScriptValue::Number(-a)),
+			_ => bail!("Cannot negate a {}", self.type_name()),
+		}
+	}
+}
 
-	fn neg(self) -> ScriptValue {
+/// Formats values for print(), with units.  Strings inside lists are quoted so that list items stay distinct.
+impl fmt::Display for ScriptValue {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
-			ScriptValue::Number(a) => ScriptValue::Number(-a),
-			_ => panic!("Cannot do math on non-numbers"),
+			ScriptValue::Number(n) => write!(f, "{}", n),
+			ScriptValue::String(s) => f.write_str(s),
+			ScriptValue::Bool(b) => write!(f, "{}", b),
+			ScriptValue::List(items) => {
+				f.write_str("[")?;
+				for (i, item) in items.iter().enumerate() {
+					if i > 0 {
+						f.write_str(", ")?;
+					}
+					match item {
+						ScriptValue::String(s) => write!(f, "{:?}", s)?,
+						item => write!(f, "{}", item)?,
+					}
+				}
+				f.write_str("]")
+			},
+			ScriptValue::Range { start, step, num } => write!(f, "{}..{} step {}", start, *start + *step * (*num as i64).into(), step),
+			ScriptValue::Function(name) => write!(f, "<fn {}>", name),
+			ScriptValue::Null => f.write_str("null"),
 		}
 	}
 }
@@ -70,3 +231,31 @@ impl TryFrom<ScriptValue> for String {
 		}
 	}
 }
+
+impl TryFrom<ScriptValue> for bool {
+	type Error = &'static str;
+
+	fn try_from(value: ScriptValue) -> Result<Self, Self::Error> {
+		match value {
+			ScriptValue::Bool(b) => Ok(b),
+			_ => Err("Not a boolean"),
+		}
+	}
+}
+
+impl From<bool> for ScriptValue {
+	fn from(value: bool) -> Self {
+		ScriptValue::Bool(value)
+	}
+}
+
+impl TryFrom<ScriptValue> for Vec<ScriptValue> {
+	type Error = &'static str;
+
+	fn try_from(value: ScriptValue) -> Result<Self, Self::Error> {
+		match value {
+			ScriptValue::List(items) => Ok(items),
+			_ => Err("Not a list"),
+		}
+	}
+}
@@ -31,12 +31,12 @@ pub fn ffi_func(_args: TokenStream, input: TokenStream) -> TokenStream {
 		} else {
 			let err_msg = format!("{}: {} is required", func_ident, ident);
 			quote! {
-				let #arg_ident = #arg_ident.ok_or(anyhow!(#err_msg))?;
+				let #arg_ident = #arg_ident.ok_or_else(|| anyhow!(#err_msg))?;
 			}
 		};
 
 		let parser = quote! {
-			let mut #arg_ident = args.next().cloned();
+			let mut #arg_ident = args.next();
 
 			if let Some(arg) = nargs.remove(#ident) {
 				#arg_ident = Some(arg);
@@ -64,10 +64,9 @@ pub fn ffi_func(_args: TokenStream, input: TokenStream) -> TokenStream {
 	let unknown_named_err = format!("{}: unknown named argument {{}}", func_ident);
 
 	let our_func = quote! {
-		pub fn #ffi_name(&mut self, args: &[ScriptValue], nargs: &std::collections::HashMap<String, ScriptValue>) -> anyhow::Result<ScriptValue> {
+		pub fn #ffi_name(&mut self, args: Vec<ScriptValue>, mut nargs: std::collections::HashMap<String, ScriptValue>) -> anyhow::Result<ScriptValue> {
 			let arg_len = args.len();
 			let mut args = args.into_iter();
-			let mut nargs = nargs.clone();
 			#(#arg_parsers)*
 
 			if args.next().is_some() {